@@ -0,0 +1,600 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::Read,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use crate::integrity;
+use crate::object_storage::{Store, download_to};
+use crate::shards::ShardMetadata;
+use anyhow::Result;
+
+const SQLITE_HEADER: &[u8; 16] = b"SQLite format 3\0";
+
+/// How often cached shards are checked against the objects still in the bucket
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+struct CacheEntry {
+    storage_key: String,
+    size: u64,
+    last_used: u64,
+    /// Queries currently reading the file, pinned entries are never evicted
+    pins: usize,
+}
+
+#[derive(Default)]
+struct CacheIndex {
+    entries: HashMap<String, CacheEntry>,
+    total_bytes: u64,
+    clock: u64,
+}
+
+/// Sealed shards downloaded by this worker, kept on disk across queries and restarts
+pub struct ShardCache {
+    store: Store,
+    dir: PathBuf,
+    max_bytes: u64,
+    index: Mutex<CacheIndex>,
+}
+
+/// A cached shard file, pinned in the cache until dropped
+pub struct CachedShard {
+    cache: Arc<ShardCache>,
+    file_name: String,
+    path: PathBuf,
+}
+
+impl CachedShard {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for CachedShard {
+    fn drop(&mut self) {
+        self.cache.unpin(&self.file_name);
+    }
+}
+
+/// Drop cached shards deleted from the bucket, at startup and then periodically.
+/// Workers that were offline while an object was collected catch up on their first sweep.
+pub async fn start_sweeper(cache: Arc<ShardCache>) {
+    let mut i = tokio::time::interval(SWEEP_INTERVAL);
+
+    loop {
+        i.tick().await;
+
+        if let Err(e) = cache.remove_deleted().await {
+            eprintln!("cache: could not check for deleted shards: {}", e);
+        }
+    }
+}
+
+impl ShardCache {
+    /// Open the cache directory, keeping every intact shard file from previous runs
+    pub fn open(store: Store, dir: PathBuf, max_bytes: u64) -> Result<ShardCache> {
+        std::fs::create_dir_all(&dir)?;
+
+        let mut files = vec![];
+
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let file_name = entry.file_name().to_string_lossy().to_string();
+
+            if !file_name.ends_with(".db") || verify_file(&file_name, &path).is_err() {
+                println!("cache: removing {}", path.display());
+                std::fs::remove_file(&path)?;
+                continue;
+            }
+
+            let metadata = entry.metadata()?;
+            files.push((metadata.modified()?, file_name, metadata.len()));
+        }
+
+        // Least recently used first, so the clock preserves the previous order
+        files.sort();
+
+        let mut index = CacheIndex::default();
+
+        for (_, file_name, size) in files {
+            index.clock += 1;
+            index.total_bytes += size;
+            index.entries.insert(
+                file_name.clone(),
+                CacheEntry {
+                    storage_key: storage_key_of(&file_name),
+                    size,
+                    last_used: index.clock,
+                    pins: 0,
+                },
+            );
+        }
+
+        println!(
+            "cache: {} shard(s), {} of {} bytes used",
+            index.entries.len(),
+            index.total_bytes,
+            max_bytes
+        );
+
+        Ok(ShardCache {
+            store,
+            dir,
+            max_bytes,
+            index: Mutex::new(index),
+        })
+    }
+
+    /// Bytes of shard files currently in the cache
+    pub fn used_bytes(&self) -> u64 {
+        self.index.lock().unwrap().total_bytes
+    }
+
+    /// Get the local copy of a shard object, downloading it on a miss
+    pub async fn get(self: &Arc<Self>, shard: &ShardMetadata) -> Result<CachedShard> {
+        let file_name = cache_file_name(&shard.storage_key);
+        let path = self.dir.join(&file_name);
+
+        if self.pin_existing(&file_name, &path) {
+            return Ok(self.cached(file_name, path));
+        }
+
+        let partial = self
+            .dir
+            .join(format!("{}.partial-{}", &file_name, uuid::Uuid::new_v4()));
+
+        let size = match self.download(shard, &partial).await {
+            Ok(size) => size,
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&partial).await;
+                return Err(e);
+            }
+        };
+
+        tokio::fs::rename(&partial, &path).await?;
+
+        {
+            let mut index = self.index.lock().unwrap();
+            index.clock += 1;
+
+            let clock = index.clock;
+
+            match index.entries.get_mut(&file_name) {
+                // Downloaded concurrently by another query, same object under the same name
+                Some(entry) => {
+                    entry.pins += 1;
+                    entry.last_used = clock;
+                }
+                None => {
+                    index.total_bytes += size;
+                    index.entries.insert(
+                        file_name.clone(),
+                        CacheEntry {
+                            storage_key: shard.storage_key.clone(),
+                            size,
+                            last_used: clock,
+                            pins: 1,
+                        },
+                    );
+                }
+            }
+        }
+
+        self.evict();
+
+        Ok(self.cached(file_name, path))
+    }
+
+    fn cached(self: &Arc<Self>, file_name: String, path: PathBuf) -> CachedShard {
+        CachedShard {
+            cache: self.clone(),
+            file_name,
+            path,
+        }
+    }
+
+    /// Pin a cache hit, dropping the entry if its file went missing or changed size
+    fn pin_existing(&self, file_name: &str, path: &Path) -> bool {
+        let mut index = self.index.lock().unwrap();
+        index.clock += 1;
+
+        let clock = index.clock;
+
+        let Some(entry) = index.entries.get_mut(file_name) else {
+            return false;
+        };
+
+        let intact = std::fs::metadata(path).is_ok_and(|metadata| metadata.len() == entry.size);
+
+        if !intact {
+            if entry.pins == 0 {
+                let size = entry.size;
+                index.entries.remove(file_name);
+                index.total_bytes -= size;
+            }
+
+            return false;
+        }
+
+        entry.pins += 1;
+        entry.last_used = clock;
+
+        // The modification time carries the LRU order over restarts
+        if let Ok(file) = std::fs::File::options().write(true).open(path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+
+        true
+    }
+
+    fn unpin(&self, file_name: &str) {
+        if let Some(entry) = self.index.lock().unwrap().entries.get_mut(file_name) {
+            entry.pins -= 1;
+        }
+
+        self.evict();
+    }
+
+    /// Compressed objects are decoded on the way, the cache only holds plain database files.
+    /// Shards are checked in full here, so corrupt files never enter the cache.
+    async fn download(&self, shard: &ShardMetadata, path: &Path) -> Result<u64> {
+        println!("cache miss: {}", &shard.storage_key);
+
+        let size = download_to(&self.store, shard, path).await?;
+
+        verify_file(&shard.storage_key, path)?;
+        integrity::check_database(&shard.storage_key, path).await?;
+
+        Ok(size)
+    }
+
+    /// Remove shards whose object is gone from the bucket, deleted by garbage collection after
+    /// retention, compaction or an erasure. Pinned shards are left for the next sweep.
+    pub async fn remove_deleted(&self) -> Result<usize> {
+        // Taken before listing, an object cached by then was uploaded before the listing started
+        let cached: Vec<(String, String)> = self
+            .index
+            .lock()
+            .unwrap()
+            .entries
+            .iter()
+            .map(|(file_name, entry)| (file_name.clone(), entry.storage_key.clone()))
+            .collect();
+
+        let keys = self.store.list("").await?;
+        let keys: HashSet<&str> = keys.iter().map(String::as_str).collect();
+
+        let mut removed = 0;
+        let mut index = self.index.lock().unwrap();
+
+        for (file_name, storage_key) in cached {
+            if keys.contains(storage_key.as_str()) {
+                continue;
+            }
+
+            let size = match index.entries.get(&file_name) {
+                Some(entry) if entry.pins == 0 => entry.size,
+                _ => continue,
+            };
+
+            index.entries.remove(&file_name);
+            index.total_bytes -= size;
+            removed += 1;
+
+            println!("cache: removing deleted {}", &storage_key);
+
+            if let Err(e) = std::fs::remove_file(self.dir.join(&file_name)) {
+                eprintln!("cache: could not remove {}: {}", &file_name, e);
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Remove least recently used, unpinned shards until the cache fits its limit
+    fn evict(&self) {
+        let mut index = self.index.lock().unwrap();
+
+        while index.total_bytes > self.max_bytes {
+            let Some(file_name) = index
+                .entries
+                .iter()
+                .filter(|(_, entry)| entry.pins == 0)
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(file_name, _)| file_name.clone())
+            else {
+                break;
+            };
+
+            if let Some(entry) = index.entries.remove(&file_name) {
+                index.total_bytes -= entry.size;
+            }
+
+            println!("cache: evicting {}", &file_name);
+
+            if let Err(e) = std::fs::remove_file(self.dir.join(&file_name)) {
+                eprintln!("cache: could not remove {}: {}", &file_name, e);
+            }
+        }
+    }
+}
+
+/// Storage keys may contain `/`, keep cache files flat in one directory
+fn cache_file_name(storage_key: &str) -> String {
+    let name: String = storage_key
+        .bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'.' | b'-' | b'_' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect();
+
+    if name.ends_with(".db") {
+        name
+    } else {
+        format!("{}.db", name)
+    }
+}
+
+/// Inverse of `cache_file_name` for the `.db` keys shards are stored under
+fn storage_key_of(file_name: &str) -> String {
+    let bytes = file_name.as_bytes();
+    let mut key = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match (bytes[i], escaped) {
+            (b'%', Some(b)) => {
+                key.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                key.push(b);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&key).into_owned()
+}
+
+/// Cheap structural check of a shard file: SQLite header and a whole number of pages.
+/// Only a file failing the check is corrupt, errors reading it are returned as they are.
+fn verify_file(storage_key: &str, path: &Path) -> Result<()> {
+    let mut file = std::fs::File::open(path)?;
+    let len = file.metadata()?.len();
+
+    let mut header = [0u8; 100];
+
+    if let Err(e) = file.read_exact(&mut header) {
+        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            return Err(integrity::corrupt(
+                storage_key,
+                "file is shorter than a header",
+            ));
+        }
+
+        return Err(e.into());
+    }
+
+    if &header[..16] != SQLITE_HEADER {
+        return Err(integrity::corrupt(storage_key, "not a SQLite database"));
+    }
+
+    let page_size = match u16::from_be_bytes([header[16], header[17]]) {
+        1 => 65536,
+        page_size => page_size as u64,
+    };
+
+    if page_size == 0 || len % page_size != 0 {
+        return Err(integrity::corrupt(storage_key, "file is truncated"));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::{NamedTempFile, TempDir};
+
+    use super::*;
+    use crate::codec::Codec;
+    use crate::db::connect_with_options;
+    use crate::integrity::corrupt_shard;
+    use crate::object_storage::LocalStore;
+    use crate::schema::create_logs_table;
+
+    /// An empty shard database, a few pages long
+    async fn database() -> NamedTempFile {
+        let file = NamedTempFile::new().unwrap();
+        let pool = connect_with_options(&file.path().display().to_string())
+            .await
+            .unwrap();
+
+        create_logs_table(&pool).await.unwrap();
+
+        sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+
+        file
+    }
+
+    fn shard(key: &str) -> ShardMetadata {
+        ShardMetadata {
+            name: "logs".to_owned(),
+            id: key.to_owned(),
+            storage_key: key.to_owned(),
+            timestamp: "2025-01-01 00:00:00".to_owned(),
+            end_timestamp: None,
+            size_bytes: 0,
+            row_count: 0,
+            codec: Codec::None,
+            sha256: None,
+        }
+    }
+
+    /// A store holding `keys` as copies of the same database, and the size of one copy
+    async fn store(keys: &[&str]) -> (TempDir, Store, u64) {
+        let dir = tempfile::tempdir().unwrap();
+        let store: Store = Arc::new(LocalStore::open(dir.path().to_owned()).unwrap());
+
+        let file = database().await;
+
+        for key in keys {
+            store.put(key, file.path()).await.unwrap();
+        }
+
+        let size = file.as_file().metadata().unwrap().len();
+
+        (dir, store, size)
+    }
+
+    fn cache(store: &Store, max_bytes: u64) -> (TempDir, Arc<ShardCache>) {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ShardCache::open(store.clone(), dir.path().to_owned(), max_bytes).unwrap();
+
+        (dir, Arc::new(cache))
+    }
+
+    fn cached(dir: &TempDir) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_shards_are_evicted_first() {
+        let (_store_dir, store, size) = store(&["a.db", "b.db", "c.db"]).await;
+        let (dir, cache) = cache(&store, 2 * size);
+
+        cache.get(&shard("a.db")).await.unwrap();
+        cache.get(&shard("b.db")).await.unwrap();
+        cache.get(&shard("a.db")).await.unwrap();
+        cache.get(&shard("c.db")).await.unwrap();
+
+        assert_eq!(cached(&dir), ["a.db", "c.db"]);
+        assert_eq!(cache.used_bytes(), 2 * size);
+
+        // Files left by a previous run are counted again
+        let reopened = ShardCache::open(store.clone(), dir.path().to_owned(), 2 * size).unwrap();
+        assert_eq!(reopened.used_bytes(), 2 * size);
+    }
+
+    #[tokio::test]
+    async fn pinned_shards_survive_eviction() {
+        let (_store_dir, store, size) = store(&["a.db", "b.db"]).await;
+        let (dir, cache) = cache(&store, size);
+
+        let a = cache.get(&shard("a.db")).await.unwrap();
+        let b = cache.get(&shard("b.db")).await.unwrap();
+
+        // Both are read, the cache goes over its limit until one is released
+        assert_eq!(cached(&dir), ["a.db", "b.db"]);
+        assert_eq!(cache.used_bytes(), 2 * size);
+
+        drop(b);
+
+        assert_eq!(cached(&dir), ["a.db"]);
+        assert_eq!(cache.used_bytes(), size);
+        assert!(a.path().exists());
+    }
+
+    #[tokio::test]
+    async fn corrupt_downloads_are_rejected() {
+        let (store_dir, store, size) = store(&["intact.db", "mismatch.db"]).await;
+        let (dir, cache) = cache(&store, 10 * size);
+
+        let intact = std::fs::read(store_dir.path().join("intact.db")).unwrap();
+        let truncated = NamedTempFile::new().unwrap();
+        std::fs::write(truncated.path(), &intact[..intact.len() - 100]).unwrap();
+        store.put("truncated.db", truncated.path()).await.unwrap();
+
+        let garbage = NamedTempFile::new().unwrap();
+        std::fs::write(garbage.path(), vec![7u8; intact.len()]).unwrap();
+        store.put("garbage.db", garbage.path()).await.unwrap();
+
+        let mut mismatch = shard("mismatch.db");
+        mismatch.sha256 = Some("0".repeat(64));
+
+        for shard in [shard("truncated.db"), shard("garbage.db"), mismatch] {
+            let e = cache.get(&shard).await.err().unwrap();
+            assert_eq!(
+                corrupt_shard(&e).map(|corrupt| corrupt.storage_key.as_str()),
+                Some(shard.storage_key.as_str()),
+                "{:#}",
+                e
+            );
+        }
+
+        assert!(cached(&dir).is_empty());
+        assert_eq!(cache.used_bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn io_errors_are_not_corruption() {
+        let (_store_dir, store, size) = store(&["a.db"]).await;
+
+        let garbage = NamedTempFile::new().unwrap();
+        std::fs::write(garbage.path(), b"not zstd").unwrap();
+        store.put("b.db", garbage.path()).await.unwrap();
+
+        let mut compressed = shard("b.db");
+        compressed.codec = Codec::Zstd;
+
+        let (dir, cache) = cache(&store, 10 * size);
+        let e = cache.get(&compressed).await.err().unwrap();
+        assert!(corrupt_shard(&e).is_some(), "{:#}", e);
+
+        // Downloads fail to write, the shards themselves are fine
+        std::fs::remove_dir(dir.path()).unwrap();
+
+        for shard in [shard("a.db"), compressed] {
+            let e = cache.get(&shard).await.err().unwrap();
+            assert!(corrupt_shard(&e).is_none(), "{:#}", e);
+        }
+    }
+
+    #[tokio::test]
+    async fn shards_deleted_from_the_bucket_are_removed() {
+        let (_store_dir, store, size) = store(&["logs/a.db", "logs/b.db"]).await;
+        let (dir, cache) = cache(&store, 10 * size);
+
+        cache.get(&shard("logs/a.db")).await.unwrap();
+        let b = cache.get(&shard("logs/b.db")).await.unwrap();
+
+        store.delete("logs/a.db").await.unwrap();
+        store.delete("logs/b.db").await.unwrap();
+
+        // Pinned shards are kept until a later sweep
+        assert_eq!(cache.remove_deleted().await.unwrap(), 1);
+        assert_eq!(cached(&dir), ["logs%2Fb.db"]);
+
+        drop(b);
+
+        assert_eq!(cache.remove_deleted().await.unwrap(), 1);
+        assert!(cached(&dir).is_empty());
+        assert_eq!(cache.used_bytes(), 0);
+    }
+
+    #[test]
+    fn cache_file_names_map_back_to_storage_keys() {
+        for key in ["logs.1.a.db", "logs/2025/a b.db", "100%.db"] {
+            assert_eq!(storage_key_of(&cache_file_name(key)), key);
+        }
+    }
+}
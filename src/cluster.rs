@@ -0,0 +1,198 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::{
+    messages::{Message, MessageHeartbeat},
+    protocol::Hello,
+    state::{ApiState, WorkerQueue},
+};
+
+/// How often workers report their state
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Workers silent for longer are considered dead and disconnected
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Dead workers stay listed this long, so a crash is visible in the cluster status
+const DEAD_RETENTION_SECS: i64 = 60 * 60;
+
+/// A worker as last seen by the coordinator
+#[derive(Serialize, Debug, Clone)]
+pub struct WorkerStatus {
+    pub id: String,
+    pub address: String,
+    /// Negotiated in the handshake
+    pub protocol_version: u32,
+    pub capabilities: Vec<String>,
+    pub alive: bool,
+    /// Gets no new ingest, queries or jobs, and exits once its shards are registered
+    pub draining: bool,
+    /// Unix timestamps in seconds
+    pub connected_at: i64,
+    pub last_heartbeat: i64,
+    pub active_shard: Option<String>,
+    pub active_rows: i64,
+    /// Shards sealed but not registered in the catalog yet
+    pub sealing_shards: usize,
+    pub cache_bytes: u64,
+    /// Compactions and rewrites running on the worker
+    pub running_jobs: usize,
+    pub running_queries: usize,
+    /// Storage keys of the shards the worker is downloading
+    pub downloading: Vec<String>,
+    /// Commands waiting on the coordinator to be sent to the worker
+    pub queued_commands: usize,
+    /// Ingested batches and messages sent to the worker by the ingest router
+    pub routed_batches: u64,
+    pub routed_messages: u64,
+}
+
+/// Add a worker after its handshake, replacing an earlier connection with the same id
+pub async fn register(state: &ApiState, hello: &Hello, protocol_version: u32, address: String) {
+    let now = time::UtcDateTime::now().unix_timestamp();
+
+    state.cluster.lock().await.insert(
+        hello.worker_id.clone(),
+        WorkerStatus {
+            id: hello.worker_id.clone(),
+            address,
+            protocol_version,
+            capabilities: hello.capabilities.clone(),
+            alive: true,
+            draining: false,
+            connected_at: now,
+            last_heartbeat: now,
+            active_shard: None,
+            active_rows: 0,
+            sealing_shards: 0,
+            cache_bytes: 0,
+            running_jobs: 0,
+            running_queries: 0,
+            downloading: vec![],
+            queued_commands: 0,
+            routed_batches: 0,
+            routed_messages: 0,
+        },
+    );
+}
+
+pub async fn heartbeat(state: &ApiState, worker_id: &str, heartbeat: &MessageHeartbeat) {
+    if let Some(worker) = state.cluster.lock().await.get_mut(worker_id) {
+        worker.alive = true;
+        worker.last_heartbeat = time::UtcDateTime::now().unix_timestamp();
+        worker.active_shard = heartbeat.active_shard.clone();
+        worker.active_rows = heartbeat.active_rows;
+        worker.sealing_shards = heartbeat.sealing_shards;
+        worker.cache_bytes = heartbeat.cache_bytes;
+        worker.running_jobs = heartbeat.running_jobs;
+        worker.running_queries = heartbeat.running_queries;
+        worker.downloading = heartbeat.downloading.clone();
+    }
+}
+
+/// Whether a worker was downloading a shard as of its last heartbeat
+pub async fn downloading(state: &ApiState, worker_id: &str, storage_key: &str) -> bool {
+    state
+        .cluster
+        .lock()
+        .await
+        .get(worker_id)
+        .is_some_and(|worker| worker.downloading.iter().any(|key| key == storage_key))
+}
+
+pub async fn routed(state: &ApiState, worker_id: &str, messages: usize) {
+    if let Some(worker) = state.cluster.lock().await.get_mut(worker_id) {
+        worker.routed_batches += 1;
+        worker.routed_messages += messages as u64;
+    }
+}
+
+pub async fn mark_dead(state: &ApiState, worker_id: &str) {
+    if let Some(worker) = state.cluster.lock().await.get_mut(worker_id) {
+        worker.alive = false;
+    }
+}
+
+/// Stop sending new work to a worker and tell it to drain. Commands queued before
+/// are still handled, as the queue is written in order.
+pub async fn drain(state: &ApiState, worker_id: &str) -> Result<()> {
+    let queue = state
+        .workers
+        .lock()
+        .await
+        .get(worker_id)
+        .cloned()
+        .with_context(|| format!("worker {} is not connected", worker_id))?;
+
+    if let Some(worker) = state.cluster.lock().await.get_mut(worker_id) {
+        worker.draining = true;
+    }
+
+    println!("draining worker {}", worker_id);
+
+    queue.send(Message::Drain)
+}
+
+/// Whether the connection a command was queued on is gone
+pub async fn worker_lost(state: &ApiState, worker_id: &str, queue: &WorkerQueue) -> bool {
+    !state
+        .workers
+        .lock()
+        .await
+        .get(worker_id)
+        .is_some_and(|current| current.same(queue))
+}
+
+/// Workers that must not be given new work
+pub async fn draining(state: &ApiState) -> HashSet<String> {
+    state
+        .cluster
+        .lock()
+        .await
+        .values()
+        .filter(|worker| worker.draining)
+        .map(|worker| worker.id.clone())
+        .collect()
+}
+
+/// Work in progress on each worker as of its last heartbeat: queries, jobs and uploads
+pub async fn backlogs(state: &ApiState) -> HashMap<String, usize> {
+    state
+        .cluster
+        .lock()
+        .await
+        .values()
+        .map(|worker| {
+            let backlog = worker.running_queries + worker.running_jobs + worker.sealing_shards;
+            (worker.id.clone(), backlog)
+        })
+        .collect()
+}
+
+/// All known workers, with the current length of their command queues
+pub async fn list(state: &ApiState) -> Vec<WorkerStatus> {
+    let now = time::UtcDateTime::now().unix_timestamp();
+
+    let mut cluster = state.cluster.lock().await;
+
+    cluster.retain(|_, worker| worker.alive || worker.last_heartbeat > now - DEAD_RETENTION_SECS);
+
+    let mut workers: Vec<WorkerStatus> = cluster.values().cloned().collect();
+
+    drop(cluster);
+
+    let queues = state.workers.lock().await;
+
+    for worker in workers.iter_mut() {
+        if let Some(queue) = queues.get(&worker.id) {
+            worker.queued_commands = queue.len();
+        }
+    }
+
+    workers.sort_by(|a, b| a.id.cmp(&b.id));
+
+    workers
+}
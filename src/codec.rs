@@ -0,0 +1,68 @@
+use std::path::Path;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+
+/// Compression level for zstd, a good ratio on log text without slowing down rotation
+const ZSTD_LEVEL: i32 = 3;
+
+/// How a shard object is encoded in object storage, set per index
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum Codec {
+    /// The raw SQLite file
+    #[default]
+    None,
+    Zstd,
+}
+
+impl Codec {
+    /// Encode a database file into a new temporary file
+    pub async fn encode(self, path: &Path) -> Result<NamedTempFile> {
+        let path = path.to_owned();
+
+        tokio::task::spawn_blocking(move || {
+            let encoded = NamedTempFile::new()?;
+            let mut source = std::fs::File::open(&path)?;
+
+            match self {
+                Codec::None => {
+                    std::io::copy(&mut source, &mut encoded.as_file())?;
+                }
+                Codec::Zstd => {
+                    zstd::stream::copy_encode(source, encoded.as_file(), ZSTD_LEVEL)?;
+                }
+            }
+
+            Ok(encoded)
+        })
+        .await?
+    }
+
+    /// Decode an object downloaded to `source` into `target`, returning the decoded size
+    pub async fn decode(self, source: &Path, target: &Path) -> Result<u64> {
+        let source = source.to_owned();
+        let target = target.to_owned();
+
+        tokio::task::spawn_blocking(move || {
+            let mut input = std::fs::File::open(&source)?;
+            let mut output = std::fs::File::create(&target)?;
+
+            match self {
+                Codec::None => {
+                    std::io::copy(&mut input, &mut output)?;
+                }
+                Codec::Zstd => {
+                    zstd::stream::copy_decode(input, &mut output)?;
+                }
+            }
+
+            output.sync_all()?;
+
+            Ok(output.metadata()?.len())
+        })
+        .await?
+    }
+}
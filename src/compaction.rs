@@ -0,0 +1,169 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{Result, bail};
+
+use crate::{
+    codec::Codec,
+    gc,
+    indices::{IndexSettings, list_indices},
+    jobs,
+    messages::{Message, MessageCompactRequest},
+    shards::{ShardMetadata, insert_shard, retire_shard},
+    state::ApiState,
+};
+
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(60);
+const COMPACTION_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+const MAX_SHARDS_PER_COMPACTION: usize = 64;
+
+/// Used for indices whose rotation policy has no size limit
+const DEFAULT_TARGET_BYTES: i64 = 256 * 1024 * 1024;
+
+pub async fn start_compaction(state: ApiState) -> Result<()> {
+    let mut i = tokio::time::interval(COMPACTION_INTERVAL);
+
+    loop {
+        i.tick().await;
+
+        if let Err(e) = compact(&state).await {
+            eprintln!("compaction failed: {}", e);
+        }
+    }
+}
+
+async fn compact(state: &ApiState) -> Result<()> {
+    let indices: HashMap<String, IndexSettings> = list_indices(&state.master_db)
+        .await?
+        .into_iter()
+        .map(|index| (index.name.clone(), index))
+        .collect();
+
+    let names: Vec<(String,)> = sqlx::query_as("SELECT DISTINCT name FROM shards")
+        .fetch_all(&state.master_db)
+        .await?;
+
+    for (name,) in names {
+        let index = indices.get(&name);
+
+        // Compacted shards grow up to the size a busy index would rotate at
+        let target_bytes = index
+            .map(|index| index.rotation.clone())
+            .unwrap_or_default()
+            .max_bytes
+            .unwrap_or(DEFAULT_TARGET_BYTES);
+
+        let codec = index.map(|index| index.compression).unwrap_or_default();
+
+        let shards = sqlx::query_as::<_, ShardMetadata>(
+            "SELECT * FROM shards WHERE name = ?1 AND corruption IS NULL ORDER BY timestamp",
+        )
+        .bind(&name)
+        .fetch_all(&state.master_db)
+        .await?;
+
+        for group in find_candidates(&shards, target_bytes) {
+            if let Err(e) = compact_group(state, &group, codec).await {
+                eprintln!("could not compact shards of {}: {}", &name, e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs of adjacent shards smaller than a quarter of the target size,
+/// which together stay within it
+fn find_candidates(shards: &[ShardMetadata], target_bytes: i64) -> Vec<Vec<ShardMetadata>> {
+    let small_bytes = target_bytes / 4;
+
+    let mut groups = vec![];
+    let mut group: Vec<ShardMetadata> = vec![];
+    let mut group_bytes = 0;
+
+    for shard in shards {
+        let fits = shard.size_bytes < small_bytes
+            && group_bytes + shard.size_bytes <= target_bytes
+            && group.len() < MAX_SHARDS_PER_COMPACTION;
+
+        if !fits {
+            if group.len() > 1 {
+                groups.push(std::mem::take(&mut group));
+            } else {
+                group.clear();
+            }
+
+            group_bytes = 0;
+
+            if shard.size_bytes >= small_bytes {
+                continue;
+            }
+        }
+
+        group_bytes += shard.size_bytes;
+        group.push(shard.clone());
+    }
+
+    if group.len() > 1 {
+        groups.push(group);
+    }
+
+    groups
+}
+
+async fn compact_group(state: &ApiState, shards: &[ShardMetadata], codec: Codec) -> Result<()> {
+    let id = uuid::Uuid::new_v4().to_string();
+
+    println!("compacting {} shard(s)", shards.len());
+
+    let job = jobs::dispatch(
+        state,
+        &id,
+        Message::CompactRequest(MessageCompactRequest {
+            id: id.clone(),
+            shards: shards.to_vec(),
+            codec,
+        }),
+    )
+    .await?;
+
+    let merged = match jobs::wait_for_response(state, &job, COMPACTION_TIMEOUT).await? {
+        Message::CompactResponse(response) => match response.shard {
+            Some(shard) => shard,
+            None => bail!("worker failed: {}", response.error.unwrap_or_default()),
+        },
+        _ => bail!("unexpected response to compaction {}", id),
+    };
+
+    let mut tx = state.master_db.begin().await?;
+
+    for shard in shards {
+        let removed = sqlx::query("DELETE FROM shards WHERE id = ?1 AND storage_key = ?2")
+            .bind(&shard.id)
+            .bind(&shard.storage_key)
+            .execute(&mut *tx)
+            .await?;
+
+        // Rewritten or expired in the meantime, the merged object is stale
+        if removed.rows_affected() == 0 {
+            tx.rollback().await?;
+            gc::schedule_deletion(&state.master_db, &merged.storage_key, 0).await?;
+
+            bail!("shard {} changed during compaction", &shard.id);
+        }
+
+        retire_shard(&mut *tx, &shard.id).await?;
+        gc::schedule_deletion(&mut *tx, &shard.storage_key, gc::GRACE_PERIOD_SECS).await?;
+    }
+
+    insert_shard(&mut *tx, &merged).await?;
+
+    tx.commit().await?;
+
+    println!(
+        "compacted {} shard(s) into {}",
+        shards.len(),
+        &merged.storage_key
+    );
+
+    Ok(())
+}
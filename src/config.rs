@@ -0,0 +1,138 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+/// Object storage settings. Flags take precedence over `SHARDY_*` environment
+/// variables, which take precedence over the `[storage]` table of the config file.
+#[derive(clap::Args, Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Object storage backend, `s3` (default) or `local`
+    #[arg(long, env = "SHARDY_STORAGE")]
+    pub storage: Option<String>,
+
+    /// Directory holding the objects of the `local` backend, defaults to a directory in the system temp dir
+    #[arg(long, env = "SHARDY_STORAGE_DIR")]
+    pub storage_dir: Option<PathBuf>,
+
+    /// S3 endpoint, e.g. `http://localhost:9000` for MinIO, AWS when unset
+    #[arg(long, env = "SHARDY_S3_ENDPOINT")]
+    pub s3_endpoint: Option<String>,
+
+    /// S3 region, taken from the AWS environment and profile when unset
+    #[arg(long, env = "SHARDY_S3_REGION")]
+    pub s3_region: Option<String>,
+
+    /// Bucket holding the shards, defaults to `logs`
+    #[arg(long, env = "SHARDY_S3_BUCKET")]
+    pub s3_bucket: Option<String>,
+
+    /// Prefix of every object key within the bucket
+    #[arg(long, env = "SHARDY_S3_PREFIX")]
+    pub s3_prefix: Option<String>,
+
+    /// Address the bucket in the path instead of the host name, needed by MinIO
+    #[arg(long, env = "SHARDY_S3_PATH_STYLE")]
+    pub s3_path_style: Option<bool>,
+
+    /// Static access key, the standard AWS credential chain is used when unset
+    #[arg(long, env = "SHARDY_S3_ACCESS_KEY_ID")]
+    pub s3_access_key_id: Option<String>,
+
+    /// Static secret key, used together with the access key
+    #[arg(long, env = "SHARDY_S3_SECRET_ACCESS_KEY", hide_env_values = true)]
+    pub s3_secret_access_key: Option<String>,
+
+    /// Part size of multipart uploads in megabytes, smaller shards are uploaded in one request
+    #[arg(long, env = "SHARDY_S3_PART_SIZE_MB")]
+    pub s3_part_size_mb: Option<u64>,
+
+    /// Parts of one shard uploaded in parallel
+    #[arg(long, env = "SHARDY_S3_UPLOAD_CONCURRENCY")]
+    pub s3_upload_concurrency: Option<usize>,
+
+    /// Create the bucket at startup if it does not exist
+    #[arg(long, env = "SHARDY_S3_CREATE_BUCKET")]
+    pub s3_create_bucket: Option<bool>,
+}
+
+impl StorageConfig {
+    /// Fill settings not given on the command line or in the environment from `fallback`
+    pub fn or(self, fallback: StorageConfig) -> StorageConfig {
+        StorageConfig {
+            storage: self.storage.or(fallback.storage),
+            storage_dir: self.storage_dir.or(fallback.storage_dir),
+            s3_endpoint: self.s3_endpoint.or(fallback.s3_endpoint),
+            s3_region: self.s3_region.or(fallback.s3_region),
+            s3_bucket: self.s3_bucket.or(fallback.s3_bucket),
+            s3_prefix: self.s3_prefix.or(fallback.s3_prefix),
+            s3_path_style: self.s3_path_style.or(fallback.s3_path_style),
+            s3_access_key_id: self.s3_access_key_id.or(fallback.s3_access_key_id),
+            s3_secret_access_key: self.s3_secret_access_key.or(fallback.s3_secret_access_key),
+            s3_part_size_mb: self.s3_part_size_mb.or(fallback.s3_part_size_mb),
+            s3_upload_concurrency: self
+                .s3_upload_concurrency
+                .or(fallback.s3_upload_concurrency),
+            s3_create_bucket: self.s3_create_bucket.or(fallback.s3_create_bucket),
+        }
+    }
+}
+
+/// Addresses of the coordinator. Flags take precedence over `SHARDY_*` environment
+/// variables, which take precedence over the `[network]` table of the config file.
+#[derive(clap::Args, Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// Address the coordinator accepts worker connections on, `0.0.0.0:6666` by default
+    #[arg(long, env = "SHARDY_WORKER_LISTEN")]
+    pub worker_listen: Option<String>,
+
+    /// Address the coordinator serves its HTTP API on, `0.0.0.0:3000` by default
+    #[arg(long, env = "SHARDY_HTTP_LISTEN")]
+    pub http_listen: Option<String>,
+
+    /// Address workers connect to, `127.0.0.1:6666` by default
+    #[arg(long, env = "SHARDY_COORDINATOR")]
+    pub coordinator: Option<String>,
+}
+
+impl NetworkConfig {
+    /// Fill settings not given on the command line or in the environment from `fallback`
+    pub fn or(self, fallback: NetworkConfig) -> NetworkConfig {
+        NetworkConfig {
+            worker_listen: self.worker_listen.or(fallback.worker_listen),
+            http_listen: self.http_listen.or(fallback.http_listen),
+            coordinator: self.coordinator.or(fallback.coordinator),
+        }
+    }
+
+    pub fn worker_listen(&self) -> &str {
+        self.worker_listen.as_deref().unwrap_or("0.0.0.0:6666")
+    }
+
+    pub fn http_listen(&self) -> &str {
+        self.http_listen.as_deref().unwrap_or("0.0.0.0:3000")
+    }
+
+    pub fn coordinator(&self) -> &str {
+        self.coordinator.as_deref().unwrap_or("127.0.0.1:6666")
+    }
+}
+
+/// Contents of the TOML config file passed with `--config`
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub storage: StorageConfig,
+    pub network: NetworkConfig,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<ConfigFile> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read config file {}", path.display()))?;
+
+        toml::from_str(&contents).with_context(|| format!("invalid config file {}", path.display()))
+    }
+}
@@ -1,91 +1,218 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpListener;
-
-use crate::messages::Message;
-use crate::state::ApiState;
+use std::net::SocketAddr;
+
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::cluster::{self, HEARTBEAT_TIMEOUT};
+use crate::indices::list_indices;
+use crate::ingest;
+use crate::jobs;
+use crate::messages::{Message, MessageShardRegistered};
+use crate::protocol;
+use crate::shards;
+use crate::state::{ApiState, WorkerQueue};
 use anyhow::Result;
 
-pub async fn start_coordinator(state: ApiState) -> Result<()> {
-    let listener = TcpListener::bind("0.0.0.0:6666").await?;
+pub async fn start_coordinator(state: ApiState, listener: TcpListener) -> Result<()> {
+    println!("accepting workers on {}", listener.local_addr()?);
 
     loop {
-        let (socket, _) = listener.accept().await?;
-        let (mut r, mut w) = socket.into_split();
+        let (socket, address) = listener.accept().await?;
 
-        let state_copy = state.clone();
+        let state = state.clone();
 
+        // The handshake of a slow worker must not hold up the others
         tokio::spawn(async move {
-            loop {
-                let command = { state_copy.commands.lock().await.pop() };
+            if let Err(e) = serve_worker(state, socket, address).await {
+                eprintln!("worker at {}: {:#}", address, e);
+            }
+        });
+    }
+}
 
-                if let Some(command) = command {
-                    println!("new command: {}", command);
+async fn serve_worker(state: ApiState, socket: TcpStream, address: SocketAddr) -> Result<()> {
+    let (mut r, mut w) = socket.into_split();
 
-                    let buf = command.into_bytes();
+    let (hello, welcome) = protocol::server_handshake(&mut r, &mut w, &[]).await?;
+    let worker_id = hello.worker_id.clone();
+    let encoding = welcome.encoding;
 
-                    w.write_all(&buf.len().to_be_bytes())
-                        .await
-                        .expect("failed to write length");
-                    w.write_all(&buf)
-                        .await
-                        .expect("failed to write data to socket");
+    cluster::register(
+        &state,
+        &hello,
+        welcome.protocol_version,
+        address.to_string(),
+    )
+    .await;
 
-                    w.flush().await.expect("could not flush");
-                }
+    let (queue, mut commands) = WorkerQueue::new();
+
+    // New workers start with the current rotation settings of every index
+    for index in list_indices(&state.master_db).await? {
+        queue.send(Message::IndexSettings(index))?;
+    }
+
+    state
+        .workers
+        .lock()
+        .await
+        .insert(worker_id.clone(), queue.clone());
+
+    let writer = tokio::spawn(async move {
+        while let Some(command) = commands.recv().await {
+            println!("new command: {:?}", command);
+
+            if let Err(e) = protocol::write_message(&mut w, &command, encoding).await {
+                eprintln!("could not write to worker: {}", e);
+                break;
             }
-        });
+        }
+    });
 
-        let state_copy = state.clone();
+    println!(
+        "worker connected: {}, protocol version: {}, capabilities: {:?}, encoding: {:?}",
+        &worker_id, welcome.protocol_version, &hello.capabilities, encoding
+    );
 
-        tokio::spawn(async move {
-            println!("worker connected");
+    // Workers send a heartbeat every few seconds, silence means they are gone
+    loop {
+        match tokio::time::timeout(HEARTBEAT_TIMEOUT, protocol::read_message(&mut r, encoding))
+            .await
+        {
+            Ok(Ok(Some(message))) => handle_message(&state, &worker_id, &queue, message).await,
+            Ok(Ok(None)) => {
+                println!("disconnected: {}", &worker_id);
+                break;
+            }
+            Ok(Err(e)) => {
+                eprintln!("{}: {:#}", &worker_id, e);
+                break;
+            }
+            Err(_) => {
+                eprintln!("{}: no heartbeat for {:?}", &worker_id, HEARTBEAT_TIMEOUT);
+                break;
+            }
+        }
+    }
 
-            loop {
-                let mut len_bytes = [0u8; 4];
+    writer.abort();
 
-                match r.read_exact(&mut len_bytes).await {
-                    Ok(0) => {
-                        println!("disconnected");
-                        break;
-                    }
-                    Err(e) => {
-                        eprintln!("{}", e);
-                        break;
-                    }
-                    _ => {}
-                }
+    {
+        // A reconnected worker with the same id has its own queue by now
+        let mut workers = state.workers.lock().await;
 
-                let len = u32::from_be_bytes(len_bytes) as usize;
+        if workers
+            .get(&worker_id)
+            .is_some_and(|current| current.same(&queue))
+        {
+            workers.remove(&worker_id);
+            drop(workers);
 
-                let mut message = vec![0; len];
+            cluster::mark_dead(&state, &worker_id).await;
 
-                match r.read_exact(&mut message).await {
-                    Ok(n) => {
-                        if n == 0 {
-                            continue;
-                        }
+            if let Err(e) = ingest::worker_disconnected(&state.master_db, &worker_id).await {
+                eprintln!("could not replay batches of {}: {}", &worker_id, e);
+            }
+        }
+    }
 
-                        let message =
-                            String::from_utf8(message.into()).expect("could not read string");
+    Ok(())
+}
 
-                        let message: Message =
-                            serde_json::from_str(&message).expect("could not parse message");
+async fn handle_message(state: &ApiState, worker_id: &str, queue: &WorkerQueue, message: Message) {
+    match message {
+        Message::SearchResponse(message_search_response) => {
+            state.results.lock().await.insert(
+                message_search_response.id.clone(),
+                message_search_response.clone(),
+            );
+        }
+        Message::CompactResponse(message_compact_response) => {
+            let id = message_compact_response.id.clone();
+
+            jobs::respond(
+                state,
+                &id,
+                Message::CompactResponse(message_compact_response),
+            )
+            .await;
+        }
+        Message::RewriteResponse(message_rewrite_response) => {
+            let id = message_rewrite_response.id.clone();
+
+            jobs::respond(
+                state,
+                &id,
+                Message::RewriteResponse(message_rewrite_response),
+            )
+            .await;
+        }
+        Message::LogAck(message_log_ack) => {
+            if let Err(e) = ingest::acknowledge(&state.master_db, worker_id, &message_log_ack).await
+            {
+                eprintln!("could not acknowledge batch: {}", e);
+            }
+        }
+        Message::ShardCorrupt(message_shard_corrupt) => {
+            eprintln!(
+                "shard {} is corrupt: {}",
+                &message_shard_corrupt.storage_key, &message_shard_corrupt.reason
+            );
+
+            if let Err(e) = shards::mark_corrupt(
+                &state.master_db,
+                &message_shard_corrupt.storage_key,
+                &message_shard_corrupt.reason,
+            )
+            .await
+            {
+                eprintln!("could not mark shard corrupt: {}", e);
+            }
+        }
+        Message::ShardSealed(message_shard_sealed) => {
+            let shard = message_shard_sealed.shard;
 
-                        match message {
-                            Message::SearchResponse(message_search_response) => {
-                                state_copy.results.lock().await.insert(
-                                    message_search_response.id.clone(),
-                                    message_search_response.clone(),
-                                );
-                            }
-                            _ => {}
-                        };
-                    }
-                    Err(_) => {
-                        continue;
+            println!("shard sealed by {}: {:?}", worker_id, &shard);
+
+            let error = match shards::store_shard(&state.master_db, &shard).await {
+                Ok(()) => {
+                    if let Err(e) = ingest::shard_registered(&state.master_db, &shard.id).await {
+                        eprintln!("could not clear ingest buffer: {}", e);
                     }
+
+                    None
                 }
+                Err(e) => {
+                    eprintln!("could not register shard {}: {}", &shard.id, e);
+
+                    Some(e.to_string())
+                }
+            };
+
+            let ack = Message::ShardRegistered(MessageShardRegistered {
+                id: shard.id,
+                error,
+            });
+
+            // Unacknowledged shards are sent again by the worker
+            if let Err(e) = queue.send(ack) {
+                eprintln!("could not acknowledge shard to {}: {}", worker_id, e);
             }
-        });
+        }
+        Message::ShardsHeld(message_shards_held) => {
+            if let Err(e) =
+                ingest::reclaim(&state.master_db, worker_id, &message_shards_held.shards).await
+            {
+                eprintln!("could not reclaim batches of {}: {}", worker_id, e);
+            }
+        }
+        Message::Draining => {
+            if let Err(e) = cluster::drain(state, worker_id).await {
+                eprintln!("could not drain {}: {}", worker_id, e);
+            }
+        }
+        Message::Heartbeat(message_heartbeat) => {
+            cluster::heartbeat(state, worker_id, &message_heartbeat).await;
+        }
+        _ => {}
     }
 }
@@ -0,0 +1,257 @@
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, bail};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    gc, ingest,
+    jobs::{self, Job},
+    messages::{Message, MessageLiveDeleteRequest, MessageRewriteRequest, MessageRewriteResponse},
+    shards::{DeleteFilter, ShardMetadata},
+    state::{ApiState, WorkerQueue},
+};
+
+const REWRITE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+const CONCURRENT_REWRITES: usize = 8;
+
+/// How long shards sealed on a worker get to show up in the catalog to be rewritten
+const REGISTRATION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Deserialize, Debug)]
+pub struct DeleteByQuery {
+    pub index: String,
+    #[serde(flatten)]
+    pub filter: DeleteFilter,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct DeleteByQueryResult {
+    pub rows_removed: u64,
+    pub shards_rewritten: usize,
+    /// Messages erased from batches buffered on the coordinator, rows already written from
+    /// them are counted in `rows_removed` as well
+    pub buffered_removed: u64,
+    /// Shards or workers that could not be processed, the request can be repeated for them
+    pub errors: Vec<String>,
+}
+
+pub async fn delete_by_query(
+    state: &ApiState,
+    request: &DeleteByQuery,
+) -> Result<DeleteByQueryResult> {
+    if request.filter.predicate.contains(';') {
+        bail!("predicate must be a single expression");
+    }
+
+    let mut result = DeleteByQueryResult::default();
+
+    // Shards without an end are assumed to reach up to the end of the range
+    let shards = sqlx::query_as::<_, ShardMetadata>(
+        "SELECT * FROM shards WHERE name = ?1 AND timestamp <= ?3 AND COALESCE(end_timestamp, ?3) >= ?2",
+    )
+    .bind(&request.index)
+    .bind(&request.filter.from)
+    .bind(&request.filter.to)
+    .fetch_all(&state.master_db)
+    .await?;
+
+    println!(
+        "delete by query on {}: {} shard(s), predicate: {}",
+        &request.index,
+        shards.len(),
+        &request.filter.predicate
+    );
+
+    // First, so a batch sent again after the live deletes does not bring its rows back
+    result.buffered_removed = ingest::erase(&state.master_db, &request.index, &request.filter)
+        .await
+        .context("could not erase buffered batches")?;
+
+    // Rows not uploaded yet are erased on the workers directly
+    let mut live_requests = vec![];
+
+    let workers: Vec<(String, WorkerQueue)> = state
+        .workers
+        .lock()
+        .await
+        .iter()
+        .map(|(worker_id, queue)| (worker_id.clone(), queue.clone()))
+        .collect();
+
+    for (worker_id, queue) in workers {
+        let id = uuid::Uuid::new_v4().to_string();
+
+        let message = Message::LiveDeleteRequest(MessageLiveDeleteRequest {
+            id: id.clone(),
+            index: request.index.clone(),
+            filter: request.filter.clone(),
+        });
+
+        match jobs::send(state, &id, &worker_id, &queue, message).await {
+            Ok(job) => live_requests.push(job),
+            Err(e) => result
+                .errors
+                .push(format!("live shards of worker {}: {}", worker_id, e)),
+        }
+    }
+
+    let queried: HashSet<String> = shards.iter().map(|shard| shard.id.clone()).collect();
+
+    let mut rewrites = futures::stream::iter(shards.into_iter().map(|shard| async move {
+        let outcome = rewrite_shard(state, &shard, &request.filter).await;
+        (shard, outcome)
+    }))
+    .buffer_unordered(CONCURRENT_REWRITES);
+
+    while let Some((shard, outcome)) = rewrites.next().await {
+        result.rewritten(&shard.id, outcome);
+    }
+
+    let mut pending: Vec<ShardMetadata> = vec![];
+
+    for job in live_requests {
+        match wait_for_rewrite(state, &job).await {
+            Ok(response) => {
+                result.rows_removed += response.rows_removed;
+                pending.extend(response.pending);
+
+                if let Some(error) = response.error {
+                    result.errors.push(format!("live shards: {}", error));
+                }
+            }
+            Err(e) => result
+                .errors
+                .push(format!("live shards of worker {}: {}", &job.worker_id, e)),
+        }
+    }
+
+    // Uploaded by the workers with the rows still in them, but not in the catalog read above
+    for shard in pending {
+        if queried.contains(&shard.id) {
+            continue;
+        }
+
+        let outcome = match wait_for_registration(state, &shard).await {
+            Ok(registered) => rewrite_shard(state, &registered, &request.filter).await,
+            Err(e) => Err(e),
+        };
+
+        result.rewritten(&shard.id, outcome);
+    }
+
+    Ok(result)
+}
+
+impl DeleteByQueryResult {
+    fn rewritten(&mut self, shard_id: &str, outcome: Result<u64>) {
+        match outcome {
+            Ok(0) => {}
+            Ok(rows_removed) => {
+                self.rows_removed += rows_removed;
+                self.shards_rewritten += 1;
+            }
+            Err(e) => {
+                eprintln!("could not rewrite shard {}: {}", shard_id, e);
+                self.errors.push(format!("shard {}: {}", shard_id, e));
+            }
+        }
+    }
+}
+
+/// Catalog entry of a shard sealed on a worker, once the worker registered it
+async fn wait_for_registration(state: &ApiState, shard: &ShardMetadata) -> Result<ShardMetadata> {
+    let start = Instant::now();
+
+    loop {
+        let registered = sqlx::query_as::<_, ShardMetadata>("SELECT * FROM shards WHERE id = ?1")
+            .bind(&shard.id)
+            .fetch_optional(&state.master_db)
+            .await?;
+
+        if let Some(registered) = registered {
+            return Ok(registered);
+        }
+
+        let replaced = sqlx::query("SELECT 1 FROM garbage WHERE storage_key = ?1")
+            .bind(&shard.storage_key)
+            .fetch_optional(&state.master_db)
+            .await?;
+
+        if replaced.is_some() {
+            bail!("replaced by a compaction before it could be rewritten");
+        }
+
+        if start.elapsed() > REGISTRATION_TIMEOUT {
+            bail!("not registered by its worker in time");
+        }
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
+async fn wait_for_rewrite(state: &ApiState, job: &Job) -> Result<MessageRewriteResponse> {
+    match jobs::wait_for_response(state, job, REWRITE_TIMEOUT).await? {
+        Message::RewriteResponse(response) => Ok(response),
+        _ => bail!("unexpected response to rewrite {}", &job.id),
+    }
+}
+
+/// Rewrite a sealed shard and swap its catalog entry, returns the number of rows removed
+async fn rewrite_shard(
+    state: &ApiState,
+    shard: &ShardMetadata,
+    filter: &DeleteFilter,
+) -> Result<u64> {
+    let id = uuid::Uuid::new_v4().to_string();
+
+    let job = jobs::dispatch(
+        state,
+        &id,
+        Message::RewriteRequest(MessageRewriteRequest {
+            id: id.clone(),
+            shard: shard.clone(),
+            filter: filter.clone(),
+        }),
+    )
+    .await?;
+
+    let response = wait_for_rewrite(state, &job).await?;
+
+    if let Some(error) = response.error {
+        bail!("worker failed: {}", error);
+    }
+
+    let Some(rewritten) = response.shard else {
+        return Ok(0);
+    };
+
+    let mut tx = state.master_db.begin().await?;
+
+    let updated = sqlx::query(
+        "UPDATE shards SET storage_key = ?1, size_bytes = ?2, row_count = ?3, sha256 = ?6 WHERE id = ?4 AND storage_key = ?5",
+    )
+    .bind(&rewritten.storage_key)
+    .bind(rewritten.size_bytes)
+    .bind(rewritten.row_count)
+    .bind(&shard.id)
+    .bind(&shard.storage_key)
+    .bind(&rewritten.sha256)
+    .execute(&mut *tx)
+    .await?;
+
+    // Compacted or expired in the meantime, the rewritten object is stale
+    if updated.rows_affected() == 0 {
+        tx.rollback().await?;
+        gc::schedule_deletion(&state.master_db, &rewritten.storage_key, 0).await?;
+
+        bail!("shard changed while rewriting");
+    }
+
+    gc::schedule_deletion(&mut *tx, &shard.storage_key, gc::GRACE_PERIOD_SECS).await?;
+
+    tx.commit().await?;
+
+    Ok(response.rows_removed)
+}
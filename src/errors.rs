@@ -1,9 +1,18 @@
-use axum::response::IntoResponse;
+use axum::{http::StatusCode, response::IntoResponse};
 
 impl IntoResponse for AppError {
     fn into_response(self) -> axum::response::Response {
-        todo!()
+        (StatusCode::INTERNAL_SERVER_ERROR, format!("{}", self.0)).into_response()
     }
 }
 
 pub struct AppError(pub anyhow::Error);
+
+impl<E> From<E> for AppError
+where
+    E: Into<anyhow::Error>,
+{
+    fn from(err: E) -> Self {
+        AppError(err.into())
+    }
+}
@@ -0,0 +1,93 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use sqlx::{Executor, Sqlite};
+
+use crate::object_storage::sidecar_key;
+use crate::state::ApiState;
+
+/// How long a replaced object is kept around for queries already running against it
+pub const GRACE_PERIOD_SECS: i64 = 5 * 60;
+
+const COLLECT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Mark storage keys as used by a running query
+pub async fn acquire(state: &ApiState, storage_keys: &[String]) {
+    let mut in_use = state.shards_in_use.lock().await;
+
+    for key in storage_keys {
+        *in_use.entry(key.clone()).or_insert(0) += 1;
+    }
+}
+
+pub async fn release(state: &ApiState, storage_keys: &[String]) {
+    let mut in_use = state.shards_in_use.lock().await;
+
+    for key in storage_keys {
+        if let Some(count) = in_use.get_mut(key) {
+            *count -= 1;
+
+            if *count == 0 {
+                in_use.remove(key);
+            }
+        }
+    }
+}
+
+/// Schedule an object for deletion, usually in the transaction that drops it from the catalog
+pub async fn schedule_deletion<'c, E>(executor: E, storage_key: &str, delay_secs: i64) -> Result<()>
+where
+    E: Executor<'c, Database = Sqlite>,
+{
+    let delete_after = time::UtcDateTime::now().unix_timestamp() + delay_secs;
+
+    sqlx::query("INSERT OR REPLACE INTO garbage (storage_key, delete_after) VALUES (?1, ?2)")
+        .bind(storage_key)
+        .bind(delete_after)
+        .execute(executor)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn start_garbage_collector(state: ApiState) -> Result<()> {
+    let mut i = tokio::time::interval(COLLECT_INTERVAL);
+
+    loop {
+        i.tick().await;
+
+        if let Err(e) = collect(&state).await {
+            eprintln!("garbage collection failed: {}", e);
+        }
+    }
+}
+
+async fn collect(state: &ApiState) -> Result<()> {
+    let now = time::UtcDateTime::now().unix_timestamp();
+
+    // Never delete an object the catalog still points to
+    let keys: Vec<(String,)> = sqlx::query_as(
+        "SELECT storage_key FROM garbage WHERE delete_after <= ?1 AND storage_key NOT IN (SELECT storage_key FROM shards)",
+    )
+    .bind(now)
+    .fetch_all(&state.master_db)
+    .await?;
+
+    for (key,) in keys {
+        if state.shards_in_use.lock().await.contains_key(&key) {
+            continue;
+        }
+
+        state.store.delete(&key).await?;
+        state.store.delete(&sidecar_key(&key)).await?;
+
+        println!("Object deleted: {}", state.store.url(&key));
+
+        sqlx::query("DELETE FROM garbage WHERE storage_key = ?1")
+            .bind(&key)
+            .execute(&state.master_db)
+            .await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,93 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqlitePool};
+
+use crate::codec::Codec;
+
+/// When a worker seals its active shard and starts a new one
+#[derive(Debug, FromRow, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RotationPolicy {
+    pub max_bytes: Option<i64>,
+    pub max_rows: Option<i64>,
+    pub max_age_secs: Option<i64>,
+    /// Never upload a shard without any rows
+    pub skip_empty: bool,
+}
+
+impl Default for RotationPolicy {
+    fn default() -> Self {
+        RotationPolicy {
+            max_bytes: Some(256 * 1024 * 1024),
+            max_rows: None,
+            max_age_secs: Some(60 * 60),
+            skip_empty: true,
+        }
+    }
+}
+
+impl RotationPolicy {
+    pub fn should_rotate(&self, bytes: i64, rows: i64, age: Duration) -> bool {
+        if rows == 0 && self.skip_empty {
+            return false;
+        }
+
+        self.max_bytes.is_some_and(|max_bytes| bytes >= max_bytes)
+            || self.max_rows.is_some_and(|max_rows| rows >= max_rows)
+            || self
+                .max_age_secs
+                .is_some_and(|max_age_secs| age.as_secs() as i64 >= max_age_secs)
+    }
+}
+
+#[derive(Debug, FromRow, Clone, Deserialize, Serialize)]
+pub struct IndexSettings {
+    #[serde(default)]
+    pub name: String,
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub rotation: RotationPolicy,
+    /// Shards whose newest rows are older than this are deleted, kept forever if unset
+    #[serde(default)]
+    pub retention_days: Option<i64>,
+    /// Codec new shard objects of this index are stored with
+    #[serde(default)]
+    pub compression: Codec,
+}
+
+pub async fn list_indices(pool: &SqlitePool) -> Result<Vec<IndexSettings>> {
+    let indices = sqlx::query_as::<_, IndexSettings>("SELECT * FROM indices ORDER BY name")
+        .fetch_all(pool)
+        .await?;
+
+    Ok(indices)
+}
+
+pub async fn store_index(pool: &SqlitePool, settings: &IndexSettings) -> Result<()> {
+    sqlx::query(
+        r#"
+        INSERT INTO indices (name, max_bytes, max_rows, max_age_secs, skip_empty, retention_days, compression)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+        ON CONFLICT (name) DO UPDATE SET
+            max_bytes = excluded.max_bytes,
+            max_rows = excluded.max_rows,
+            max_age_secs = excluded.max_age_secs,
+            skip_empty = excluded.skip_empty,
+            retention_days = excluded.retention_days,
+            compression = excluded.compression
+        "#,
+    )
+    .bind(&settings.name)
+    .bind(settings.rotation.max_bytes)
+    .bind(settings.rotation.max_rows)
+    .bind(settings.rotation.max_age_secs)
+    .bind(settings.rotation.skip_empty)
+    .bind(settings.retention_days)
+    .bind(settings.compression)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,332 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+use sqlx::{SqlitePool, sqlite::SqlitePoolOptions};
+
+use crate::{
+    cluster,
+    messages::{Message, MessageLog, MessageLogAck},
+    shards::{DEFAULT_INDEX, DeleteFilter},
+    state::ApiState,
+};
+
+const REDELIVERY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Batches not committed to a shard by then are sent again
+const ACK_TIMEOUT_SECS: i64 = 30;
+
+/// Batches acknowledged by a worker of a previous coordinator run are sent again if their
+/// shard is not registered by then. Longer than the default maximum shard age.
+const ORPHANED_AFTER_SECS: i64 = 2 * 60 * 60;
+
+/// Batches of a disconnected worker are sent again if it does not reconnect by then
+const REJOIN_TIMEOUT_SECS: i64 = 2 * 60;
+
+/// How long a request with `ack=stored` waits for the worker
+const STORED_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// When `POST /logs` answers
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AckMode {
+    /// Once the batch is in the buffer of the coordinator
+    #[default]
+    Accepted,
+    /// Once a worker has committed the batch to its shard
+    Stored,
+}
+
+/// Buffer a request of logs and send it to the workers picked by the ingest router, as one
+/// batch per worker. Batches stay in the buffer until the shard they were written to is
+/// registered, and are sent again if that never happens.
+pub async fn ingest(state: &ApiState, logs: Vec<String>, ack: AckMode) -> Result<Vec<String>> {
+    let now = time::UtcDateTime::now().unix_timestamp();
+
+    let mut batches = vec![];
+
+    for logs in state.ingest_router.split(state, logs).await {
+        let batch = uuid::Uuid::new_v4().to_string();
+
+        sqlx::query(
+            "INSERT INTO ingest_buffer (id, logs, received_at, dispatched_at) VALUES (?1, ?2, ?3, ?3)",
+        )
+        .bind(&batch)
+        .bind(serde_json::to_string(&logs)?)
+        .bind(now)
+        .execute(&state.master_db)
+        .await?;
+
+        // The batch is buffered already, redelivery picks it up once a worker can take it
+        if let Err(e) = send_batch(state, &batch, logs, vec![]).await {
+            eprintln!("batch {} stays buffered: {}", &batch, e);
+        }
+
+        batches.push(batch);
+    }
+
+    if ack == AckMode::Stored {
+        for batch in &batches {
+            wait_until_stored(&state.master_db, batch).await?;
+        }
+    }
+
+    Ok(batches)
+}
+
+/// Send a batch to a worker, marking it for redelivery right away if none can take it
+async fn send_batch(
+    state: &ApiState,
+    batch: &str,
+    logs: Vec<String>,
+    erased: Vec<usize>,
+) -> Result<()> {
+    let routed = state.ingest_router.pick(state, &logs).await;
+
+    let count = logs.len() - erased.len();
+    let message = Message::Log(MessageLog {
+        batch: batch.to_owned(),
+        logs,
+        erased,
+    });
+
+    match routed.and_then(|(worker_id, queue)| queue.send(message).map(|_| worker_id)) {
+        Ok(worker_id) => {
+            cluster::routed(state, &worker_id, count).await;
+
+            Ok(())
+        }
+        Err(e) => {
+            sqlx::query("UPDATE ingest_buffer SET dispatched_at = 0 WHERE id = ?1")
+                .bind(batch)
+                .execute(&state.master_db)
+                .await?;
+
+            Err(e)
+        }
+    }
+}
+
+async fn wait_until_stored(pool: &SqlitePool, batch: &str) -> Result<()> {
+    let start = Instant::now();
+
+    loop {
+        let stored: Option<(Option<String>,)> =
+            sqlx::query_as("SELECT shard_id FROM ingest_buffer WHERE id = ?1")
+                .bind(batch)
+                .fetch_optional(pool)
+                .await?;
+
+        // Gone from the buffer means its shard is registered already
+        if !matches!(stored, Some((None,))) {
+            return Ok(());
+        }
+
+        if start.elapsed() > STORED_TIMEOUT {
+            bail!("batch {} not stored yet, it stays buffered", batch);
+        }
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+/// Record the shard a worker committed a batch to
+pub async fn acknowledge(pool: &SqlitePool, worker_id: &str, ack: &MessageLogAck) -> Result<()> {
+    sqlx::query(
+        "UPDATE ingest_buffer SET shard_id = ?2, worker_id = ?3, acked_at = ?4 WHERE id = ?1",
+    )
+    .bind(&ack.batch)
+    .bind(&ack.shard)
+    .bind(worker_id)
+    .bind(time::UtcDateTime::now().unix_timestamp())
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Batches in a registered shard are safe in object storage, drop them from the buffer
+pub async fn shard_registered(pool: &SqlitePool, shard_id: &str) -> Result<()> {
+    sqlx::query("DELETE FROM ingest_buffer WHERE shard_id = ?1")
+        .bind(shard_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// The shards of a disconnected worker are lost unless it reconnects in time, otherwise
+/// their batches are sent again
+pub async fn worker_disconnected(pool: &SqlitePool, worker_id: &str) -> Result<()> {
+    let orphaned = sqlx::query(
+        "UPDATE ingest_buffer SET worker_id = NULL, disconnected_at = ?2 WHERE worker_id = ?1",
+    )
+    .bind(worker_id)
+    .bind(time::UtcDateTime::now().unix_timestamp())
+    .execute(pool)
+    .await?;
+
+    if orphaned.rows_affected() > 0 {
+        println!(
+            "{} batch(es) of worker {} are replayed unless it reconnects within {}s",
+            orphaned.rows_affected(),
+            worker_id,
+            REJOIN_TIMEOUT_SECS
+        );
+    }
+
+    Ok(())
+}
+
+/// A reconnected worker still holds these shards, their batches are safe again
+pub async fn reclaim(pool: &SqlitePool, worker_id: &str, shards: &[String]) -> Result<()> {
+    let mut reclaimed = 0;
+
+    for shard in shards {
+        reclaimed += sqlx::query(
+            "UPDATE ingest_buffer SET worker_id = ?2, disconnected_at = NULL WHERE shard_id = ?1",
+        )
+        .bind(shard)
+        .bind(worker_id)
+        .execute(pool)
+        .await?
+        .rows_affected();
+    }
+
+    if reclaimed > 0 {
+        println!("worker {} reclaimed {} batch(es)", worker_id, reclaimed);
+    }
+
+    Ok(())
+}
+
+/// Erase matching messages from buffered batches, so they are not sent again if the shard
+/// they were written to is lost. Returns the number of messages erased.
+pub async fn erase(pool: &SqlitePool, index: &str, filter: &DeleteFilter) -> Result<u64> {
+    // Batches always go to the active shards, which belong to the default index
+    if index != DEFAULT_INDEX {
+        return Ok(0);
+    }
+
+    // The predicate only sees the messages of one batch, never the catalog
+    let scratch = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await?;
+
+    let batches: Vec<(String, String, String, i64)> =
+        sqlx::query_as("SELECT id, logs, erased, received_at FROM ingest_buffer")
+            .fetch_all(pool)
+            .await?;
+
+    let mut removed = 0;
+
+    for (batch, logs, erased, received_at) in batches {
+        let mut logs: Vec<String> = serde_json::from_str(&logs)?;
+        let mut erased: Vec<usize> = serde_json::from_str(&erased)?;
+
+        let received_at = time::UtcDateTime::from_unix_timestamp(received_at)?.to_string();
+
+        let matching: Vec<usize> = filter
+            .matching_positions(&scratch, &batch, &received_at, &logs)
+            .await?
+            .into_iter()
+            .filter(|position| !erased.contains(position))
+            .collect();
+
+        if matching.is_empty() {
+            continue;
+        }
+
+        for &position in &matching {
+            logs[position].clear();
+        }
+
+        removed += matching.len() as u64;
+        erased.extend(matching);
+
+        if erased.len() == logs.len() {
+            sqlx::query("DELETE FROM ingest_buffer WHERE id = ?1")
+                .bind(&batch)
+                .execute(pool)
+                .await?;
+        } else {
+            sqlx::query("UPDATE ingest_buffer SET logs = ?2, erased = ?3 WHERE id = ?1")
+                .bind(&batch)
+                .bind(serde_json::to_string(&logs)?)
+                .bind(serde_json::to_string(&erased)?)
+                .execute(pool)
+                .await?;
+        }
+    }
+
+    scratch.close().await;
+
+    Ok(removed)
+}
+
+/// Workers of a previous run can't acknowledge anymore, but may still register their shards
+pub async fn recover_buffer(pool: &SqlitePool) -> Result<()> {
+    sqlx::query("UPDATE ingest_buffer SET worker_id = NULL, dispatched_at = 0")
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn start_redelivery(state: ApiState) -> Result<()> {
+    let mut i = tokio::time::interval(REDELIVERY_INTERVAL);
+
+    loop {
+        i.tick().await;
+
+        if let Err(e) = redeliver(&state).await {
+            eprintln!("redelivery failed: {}", e);
+        }
+    }
+}
+
+async fn redeliver(state: &ApiState) -> Result<()> {
+    let now = time::UtcDateTime::now().unix_timestamp();
+
+    let batches: Vec<(String, String, String)> = sqlx::query_as(
+        r#"
+        SELECT id, logs, erased FROM ingest_buffer
+        WHERE (shard_id IS NULL AND dispatched_at <= ?1)
+           OR (shard_id IS NOT NULL AND worker_id IS NULL AND disconnected_at <= ?3)
+           OR (shard_id IS NOT NULL AND worker_id IS NULL AND disconnected_at IS NULL AND acked_at <= ?2)
+        ORDER BY received_at
+        "#,
+    )
+    .bind(now - ACK_TIMEOUT_SECS)
+    .bind(now - ORPHANED_AFTER_SECS)
+    .bind(now - REJOIN_TIMEOUT_SECS)
+    .fetch_all(&state.master_db)
+    .await?;
+
+    if !batches.is_empty() && state.workers.lock().await.is_empty() {
+        return Ok(());
+    }
+
+    for (batch, logs, erased) in batches {
+        println!("redelivering batch {}", &batch);
+
+        sqlx::query(
+            "UPDATE ingest_buffer SET shard_id = NULL, worker_id = NULL, acked_at = NULL, disconnected_at = NULL, dispatched_at = ?2 WHERE id = ?1",
+        )
+        .bind(&batch)
+        .bind(now)
+        .execute(&state.master_db)
+        .await?;
+
+        send_batch(
+            state,
+            &batch,
+            serde_json::from_str(&logs)?,
+            serde_json::from_str(&erased)?,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,128 @@
+use std::fmt;
+use std::path::Path;
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use sqlx::sqlite::SqliteConnectOptions;
+
+/// A shard object that can't be used: it does not match its checksum or is not a valid
+/// database. Unlike download errors, retrying does not help.
+#[derive(Debug)]
+pub struct CorruptShard {
+    pub storage_key: String,
+    pub reason: String,
+}
+
+impl fmt::Display for CorruptShard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "shard {} is corrupt: {}", self.storage_key, self.reason)
+    }
+}
+
+impl std::error::Error for CorruptShard {}
+
+/// The corrupt shard behind an error, if that is what it is
+pub fn corrupt_shard(e: &anyhow::Error) -> Option<&CorruptShard> {
+    e.downcast_ref::<CorruptShard>()
+}
+
+/// Error for a shard that failed one of the checks below
+pub fn corrupt(storage_key: &str, reason: impl ToString) -> anyhow::Error {
+    CorruptShard {
+        storage_key: storage_key.to_owned(),
+        reason: reason.to_string(),
+    }
+    .into()
+}
+
+/// Error decoding an object: failures reported by the operating system, such as a full disk or
+/// too many open files, are kept as they are so the query is retried, anything else is corruption
+pub fn corrupt_unless_io(storage_key: &str, e: anyhow::Error, context: &str) -> anyhow::Error {
+    match e.downcast_ref::<std::io::Error>() {
+        Some(io) if io.raw_os_error().is_some() => e,
+        _ => corrupt(storage_key, format!("{}: {}", context, e)),
+    }
+}
+
+/// SQLite found the file is not a database or is damaged, rather than failing to read it
+fn is_corruption(e: &sqlx::Error) -> bool {
+    const SQLITE_CORRUPT: i32 = 11;
+    const SQLITE_NOTADB: i32 = 26;
+
+    e.as_database_error()
+        .and_then(|e| e.code())
+        .and_then(|code| code.parse::<i32>().ok())
+        .is_some_and(|code| matches!(code & 0xff, SQLITE_CORRUPT | SQLITE_NOTADB))
+}
+
+/// Hex encoded SHA-256 of a file
+pub async fn sha256_file(path: &Path) -> Result<String> {
+    let path = path.to_owned();
+
+    tokio::task::spawn_blocking(move || {
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut std::fs::File::open(&path)?, &mut hasher)?;
+
+        Ok(hex::encode(hasher.finalize()))
+    })
+    .await?
+}
+
+/// Compare a downloaded database with the checksum recorded when it was uploaded.
+/// Shards uploaded before checksums were recorded are accepted.
+pub async fn verify_checksum(storage_key: &str, path: &Path, expected: Option<&str>) -> Result<()> {
+    let Some(expected) = expected else {
+        return Ok(());
+    };
+
+    let actual = sha256_file(path).await?;
+
+    if actual != expected {
+        return Err(corrupt(
+            storage_key,
+            format!("checksum mismatch, expected {}, got {}", expected, actual),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Run `PRAGMA integrity_check` on a database file. Only damage SQLite reports is corruption,
+/// errors reading the file are returned as they are.
+pub async fn check_database(storage_key: &str, path: &Path) -> Result<()> {
+    let options = SqliteConnectOptions::new()
+        .filename(path)
+        .read_only(true)
+        .immutable(true);
+
+    let checked = |e: sqlx::Error| {
+        if is_corruption(&e) {
+            corrupt(storage_key, e)
+        } else {
+            e.into()
+        }
+    };
+
+    let pool = SqlitePool::connect_with(options).await.map_err(checked)?;
+
+    let problems: Result<Vec<(String,)>, sqlx::Error> = sqlx::query_as("PRAGMA integrity_check")
+        .fetch_all(&pool)
+        .await;
+
+    pool.close().await;
+
+    let problems = problems.map_err(checked)?;
+
+    match problems.first() {
+        Some((result,)) if result == "ok" => Ok(()),
+        _ => Err(corrupt(
+            storage_key,
+            problems
+                .into_iter()
+                .map(|(problem,)| problem)
+                .collect::<Vec<_>>()
+                .join(", "),
+        )),
+    }
+}
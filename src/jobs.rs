@@ -0,0 +1,97 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, anyhow};
+
+use crate::{
+    cluster,
+    messages::Message,
+    state::{ApiState, WorkerQueue},
+};
+
+/// A job queued on a worker, its response is collected with `wait_for_response`
+pub struct Job {
+    pub id: String,
+    pub worker_id: String,
+    queue: WorkerQueue,
+}
+
+/// Queue a job with `id` on a given worker
+pub async fn send(
+    state: &ApiState,
+    id: &str,
+    worker_id: &str,
+    queue: &WorkerQueue,
+    message: Message,
+) -> Result<Job> {
+    state.jobs.lock().await.insert(id.to_owned(), None);
+
+    if let Err(e) = queue.send(message) {
+        state.jobs.lock().await.remove(id);
+        return Err(e);
+    }
+
+    Ok(Job {
+        id: id.to_owned(),
+        worker_id: worker_id.to_owned(),
+        queue: queue.clone(),
+    })
+}
+
+/// Queue a job with `id` for the connected worker with the fewest pending commands
+pub async fn dispatch(state: &ApiState, id: &str, message: Message) -> Result<Job> {
+    let (worker_id, queue) = {
+        let draining = cluster::draining(state).await;
+        let workers = state.workers.lock().await;
+
+        workers
+            .iter()
+            .filter(|(worker_id, _)| !draining.contains(*worker_id))
+            .min_by_key(|(_, queue)| queue.len())
+            .map(|(worker_id, queue)| (worker_id.clone(), queue.clone()))
+            .context("no worker connected")?
+    };
+
+    send(state, id, &worker_id, &queue, message).await
+}
+
+/// Store the response of a worker, unless no one waits for it anymore
+pub async fn respond(state: &ApiState, id: &str, message: Message) {
+    if let Some(response) = state.jobs.lock().await.get_mut(id) {
+        *response = Some(message);
+    }
+}
+
+/// Wait for the worker response to a job, failing early if the worker disconnects
+pub async fn wait_for_response(state: &ApiState, job: &Job, timeout: Duration) -> Result<Message> {
+    let start = Instant::now();
+
+    let result = loop {
+        // Checked first, a response may arrive just before the connection is lost
+        let lost = cluster::worker_lost(state, &job.worker_id, &job.queue).await;
+
+        if let Some(response) = state
+            .jobs
+            .lock()
+            .await
+            .get_mut(&job.id)
+            .and_then(Option::take)
+        {
+            break Ok(response);
+        }
+
+        if lost {
+            break Err(anyhow!("worker {} disconnected", &job.worker_id));
+        }
+
+        if start.elapsed() > timeout {
+            break Err(anyhow!("job {} timed out", &job.id));
+        }
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    };
+
+    // A late response to an abandoned job is dropped
+    state.jobs.lock().await.remove(&job.id);
+
+    result
+}
@@ -1,22 +1,46 @@
-use anyhow::Result;
-use object_storage::get_s3_client;
-use std::{collections::HashMap, sync::Arc};
+use anyhow::{Context, Result};
+use sqlx::SqlitePool;
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use tokio::net::TcpListener;
 use tokio::sync::Mutex;
 use worker::init_worker;
 
+mod cache;
+mod cluster;
+mod codec;
+mod compaction;
+mod config;
 mod coordinator;
 mod db;
+mod erasure;
 mod errors;
+mod gc;
+mod indices;
+mod ingest;
+mod integrity;
+mod jobs;
 mod messages;
 mod object_storage;
+mod placement;
+mod protocol;
+mod recovery;
+mod retention;
+mod routing;
 mod schema;
 mod shards;
 mod state;
+mod vfs;
 mod web;
 mod worker;
 
+use config::{ConfigFile, NetworkConfig, StorageConfig};
 use db::connect_with_options;
-use schema::create_shards_table;
+use object_storage::open_store;
+use routing::{IngestRouter, RoutingStrategy};
+use schema::{
+    create_garbage_table, create_indices_table, create_ingest_buffer_table,
+    create_retired_shards_table, create_shards_table,
+};
 use state::ApiState;
 
 use clap::Parser;
@@ -28,9 +52,41 @@ struct Args {
     /// Name of the person to greet
     #[arg(short, long)]
     mode: String,
-}
 
-const BUCKET: &'static str = "logs";
+    /// Directory for shards cached by a worker, defaults to a directory in the system temp dir
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+
+    /// Maximum size of the worker shard cache in megabytes
+    #[arg(long, default_value_t = 10 * 1024)]
+    cache_size_mb: u64,
+
+    /// Read sealed shards page by page with ranged requests instead of downloading them
+    #[arg(long)]
+    ranged_reads: bool,
+
+    /// How the coordinator spreads ingested logs over the workers
+    #[arg(long, value_enum, default_value_t)]
+    routing: RoutingStrategy,
+
+    /// Field of JSON log messages that `--routing hash-by-field` routes by, e.g. `service`
+    #[arg(long)]
+    routing_field: Option<String>,
+
+    /// With `--mode recover`, only report what would be changed in the catalog
+    #[arg(long)]
+    dry_run: bool,
+
+    /// TOML config file, settings given as flags or environment variables take precedence
+    #[arg(long, env = "SHARDY_CONFIG")]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    storage: StorageConfig,
+
+    #[command(flatten)]
+    network: NetworkConfig,
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -38,34 +94,81 @@ async fn main() -> Result<()> {
 
     println!("Running in mode: {}", &args.mode);
 
-    if args.mode == "worker" {
-        init_worker().await?;
-    } else {
-        let cwd = std::env::current_dir()?;
+    let file = match &args.config {
+        Some(path) => ConfigFile::load(path)?,
+        None => ConfigFile::default(),
+    };
+
+    let store = open_store(&args.storage.or(file.storage)).await?;
+    let network = args.network.or(file.network);
 
-        let mut master_path = cwd.clone();
-        master_path.push("./master.db");
-        let master_path = format!("sqlite:{}", master_path.display());
-        let master_pool = connect_with_options(&master_path).await?;
+    if args.mode == "worker" {
+        let cache_dir = args
+            .cache_dir
+            .unwrap_or_else(|| std::env::temp_dir().join("shardy-cache"));
 
-        create_shards_table(&master_pool).await?;
+        init_worker(
+            store,
+            cache_dir,
+            args.cache_size_mb * 1024 * 1024,
+            args.ranged_reads,
+            &network,
+        )
+        .await?;
+    } else if args.mode == "recover" {
+        let master_pool = open_master_db().await?;
 
-        sqlx::query("SELECT 1 = 1").execute(&master_pool).await?;
+        recovery::recover(&store, &master_pool, args.dry_run)
+            .await?
+            .print(args.dry_run);
+    } else {
+        let master_pool = open_master_db().await?;
 
-        let client = get_s3_client();
+        ingest::recover_buffer(&master_pool).await?;
 
-        let commands = Arc::new(Mutex::new(vec![]));
         let search_results = Arc::new(Mutex::new(HashMap::new()));
 
         let state = ApiState {
-            client: client.clone(),
+            store,
             master_db: master_pool,
-            commands,
+            workers: Arc::new(Mutex::new(HashMap::new())),
+            cluster: Arc::new(Mutex::new(HashMap::new())),
             results: search_results,
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+            shards_in_use: Arc::new(Mutex::new(HashMap::new())),
+            ingest_router: Arc::new(IngestRouter::new(args.routing, args.routing_field)?),
         };
-        tokio::spawn(coordinator::start_coordinator(state.clone()));
-        web::init_web(state.clone()).await?;
+        let listener = TcpListener::bind(network.worker_listen())
+            .await
+            .with_context(|| format!("could not listen on {}", network.worker_listen()))?;
+
+        tokio::spawn(coordinator::start_coordinator(state.clone(), listener));
+        tokio::spawn(compaction::start_compaction(state.clone()));
+        tokio::spawn(retention::start_retention(state.clone()));
+        tokio::spawn(gc::start_garbage_collector(state.clone()));
+        tokio::spawn(ingest::start_redelivery(state.clone()));
+        web::init_web(state.clone(), network.http_listen()).await?;
     }
 
     Ok(())
 }
+
+/// The catalog of the coordinator, in the working directory
+async fn open_master_db() -> Result<SqlitePool> {
+    let cwd = std::env::current_dir()?;
+
+    let mut master_path = cwd.clone();
+    master_path.push("./master.db");
+    let master_path = format!("sqlite:{}", master_path.display());
+    let master_pool = connect_with_options(&master_path).await?;
+
+    create_shards_table(&master_pool).await?;
+    create_indices_table(&master_pool).await?;
+    create_garbage_table(&master_pool).await?;
+    create_ingest_buffer_table(&master_pool).await?;
+    create_retired_shards_table(&master_pool).await?;
+
+    sqlx::query("SELECT 1 = 1").execute(&master_pool).await?;
+
+    Ok(master_pool)
+}
@@ -1,28 +1,297 @@
 use serde::{Deserialize, Serialize};
 
-use crate::shards::{QueryResult, ShardMetadata};
+use crate::codec::Codec;
+use crate::indices::IndexSettings;
+use crate::shards::{DeleteFilter, QueryResult, ShardMetadata};
 
-#[derive(Serialize, Deserialize, Debug)]
+/// A batch of ingested log messages, buffered by the coordinator until acknowledged
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MessageLog {
-    pub log: String,
+    pub batch: String,
+    pub logs: Vec<String>,
+    /// Positions in `logs` erased while the batch was buffered, left blank to keep row ids
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub erased: Vec<usize>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// A batch committed to the shard with id `shard`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageLogAck {
+    pub batch: String,
+    pub shard: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MessageSearchRequest {
     pub query: String,
     pub id: String,
     pub shard: ShardMetadata,
 }
 
+/// Query against the shards a worker has not uploaded yet
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageLiveSearchRequest {
+    pub query: String,
+    pub id: String,
+    pub index: String,
+    /// Shards already queried through the catalog or merged into one of them, skipped by the worker
+    pub exclude: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MessageSearchResponse {
     pub id: String,
+    #[serde(with = "columnar")]
     pub payload: QueryResult,
+    /// Set when the shard could not be queried, the coordinator retries on another worker
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// Result sets on the wire: column names once, then one value per column and row,
+/// instead of a map with every column name per row
+mod columnar {
+    use std::collections::{BTreeSet, HashMap};
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::shards::QueryResult;
+
+    #[derive(Serialize, Deserialize)]
+    struct ColumnarResult {
+        columns: Vec<String>,
+        /// Union of the columns of all rows, rows of merged results can differ
+        keys: Vec<String>,
+        rows: Vec<Vec<Option<String>>>,
+    }
+
+    pub fn serialize<S: Serializer>(
+        result: &QueryResult,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let keys: Vec<String> = result
+            .items
+            .iter()
+            .flat_map(|row| row.keys())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .cloned()
+            .collect();
+
+        let rows = result
+            .items
+            .iter()
+            .map(|row| keys.iter().map(|key| row.get(key).cloned()).collect())
+            .collect();
+
+        ColumnarResult {
+            columns: result.columns.clone(),
+            keys,
+            rows,
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<QueryResult, D::Error> {
+        let columnar = ColumnarResult::deserialize(deserializer)?;
+
+        let items = columnar
+            .rows
+            .into_iter()
+            .map(|row| {
+                columnar
+                    .keys
+                    .iter()
+                    .zip(row)
+                    .filter_map(|(key, value)| Some((key.clone(), value?)))
+                    .collect::<HashMap<_, _>>()
+            })
+            .collect();
+
+        Ok(QueryResult {
+            items,
+            columns: columnar.columns,
+            failed_shards: vec![],
+        })
+    }
+}
+
+/// Merge sealed shards into one new shard, without registering it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageCompactRequest {
+    pub id: String,
+    pub shards: Vec<ShardMetadata>,
+    /// Codec of the merged shard, the current setting of the index
+    #[serde(default)]
+    pub codec: Codec,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageCompactResponse {
+    pub id: String,
+    pub shard: Option<ShardMetadata>,
+    pub error: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Erase rows from a sealed shard, uploading the result under a new key
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageRewriteRequest {
+    pub id: String,
+    pub shard: ShardMetadata,
+    pub filter: DeleteFilter,
+}
+
+/// Erase rows from the shards a worker has not uploaded yet
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageLiveDeleteRequest {
+    pub id: String,
+    pub index: String,
+    pub filter: DeleteFilter,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageRewriteResponse {
+    pub id: String,
+    /// New metadata of a rewritten sealed shard, `None` if it was left unchanged
+    pub shard: Option<ShardMetadata>,
+    pub rows_removed: u64,
+    pub error: Option<String>,
+    /// Sealed shards with matching rows that are uploaded already, or being uploaded.
+    /// Left alone by the worker, the coordinator rewrites them once they are registered.
+    #[serde(default)]
+    pub pending: Vec<ShardMetadata>,
+}
+
+/// A shard a worker found corrupt, left out of queries from then on
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageShardCorrupt {
+    pub storage_key: String,
+    pub reason: String,
+}
+
+/// State of a worker, sent periodically so the coordinator can tell it is alive
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageHeartbeat {
+    pub active_shard: Option<String>,
+    pub active_rows: i64,
+    pub sealing_shards: usize,
+    pub cache_bytes: u64,
+    pub running_jobs: usize,
+    /// Shard queries being answered
+    #[serde(default)]
+    pub running_queries: usize,
+    /// Storage keys of shards being downloaded, queries waiting on them are not timed out
+    #[serde(default)]
+    pub downloading: Vec<String>,
+}
+
+/// Ids of the shards a worker holds locally, sent after every handshake so batches written
+/// to them are not replayed while the worker was away
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageShardsHeld {
+    pub shards: Vec<String>,
+}
+
+/// An uploaded shard to register in the catalog, sent until the coordinator acknowledges it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageShardSealed {
+    pub shard: ShardMetadata,
+}
+
+/// Answer to `ShardSealed`, the worker sends the shard again later after an error
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageShardRegistered {
+    pub id: String,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
     Log(MessageLog),
     SearchRequest(MessageSearchRequest),
+    LiveSearchRequest(MessageLiveSearchRequest),
     SearchResponse(MessageSearchResponse),
+    IndexSettings(IndexSettings),
+    CompactRequest(MessageCompactRequest),
+    CompactResponse(MessageCompactResponse),
+    RewriteRequest(MessageRewriteRequest),
+    LiveDeleteRequest(MessageLiveDeleteRequest),
+    RewriteResponse(MessageRewriteResponse),
+    ShardCorrupt(MessageShardCorrupt),
+    LogAck(MessageLogAck),
+    Heartbeat(MessageHeartbeat),
+    ShardsHeld(MessageShardsHeld),
+    ShardSealed(MessageShardSealed),
+    ShardRegistered(MessageShardRegistered),
+    /// Sent by a worker asked to shut down, the coordinator answers with `Drain`
+    Draining,
+    /// Last command to a draining worker, it seals its shards and exits once registered
+    Drain,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn response() -> MessageSearchResponse {
+        let row = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        };
+
+        MessageSearchResponse {
+            id: "search-1".to_owned(),
+            payload: QueryResult {
+                // Merged results, not every row has every column
+                items: vec![
+                    row(&[("id", "1"), ("message", "first")]),
+                    row(&[("id", "2")]),
+                    row(&[("id", "3"), ("message", ""), ("level", "warn")]),
+                ],
+                columns: vec!["id".to_owned(), "message".to_owned()],
+                failed_shards: vec![],
+            },
+            error: None,
+        }
+    }
+
+    fn assert_same(decoded: &MessageSearchResponse, expected: &MessageSearchResponse) {
+        assert_eq!(decoded.id, expected.id);
+        assert_eq!(decoded.payload.items, expected.payload.items);
+        assert_eq!(decoded.payload.columns, expected.payload.columns);
+        assert_eq!(decoded.error, expected.error);
+    }
+
+    #[test]
+    fn columnar_results_round_trip() {
+        let expected = response();
+
+        let json: MessageSearchResponse =
+            serde_json::from_slice(&serde_json::to_vec(&expected).unwrap()).unwrap();
+        assert_same(&json, &expected);
+
+        let msgpack: MessageSearchResponse =
+            rmp_serde::from_slice(&rmp_serde::to_vec_named(&expected).unwrap()).unwrap();
+        assert_same(&msgpack, &expected);
+    }
+
+    #[test]
+    fn columnar_results_name_each_column_once() {
+        let json = serde_json::to_value(response()).unwrap();
+
+        assert_eq!(
+            json["payload"]["keys"],
+            serde_json::json!(["id", "level", "message"])
+        );
+        assert_eq!(
+            json["payload"]["rows"][1],
+            serde_json::json!(["2", null, null])
+        );
+    }
 }
@@ -1,68 +1,880 @@
-use anyhow::Result;
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow, bail};
+use async_trait::async_trait;
+use aws_config::BehaviorVersion;
 use aws_sdk_s3::Client;
 use aws_sdk_s3::config::{Credentials, Region};
+use aws_sdk_s3::error::DisplayErrorContext;
+use aws_sdk_s3::primitives::{ByteStream, Length};
+use aws_sdk_s3::types::{
+    BucketLocationConstraint, CompletedMultipartUpload, CompletedPart, CreateBucketConfiguration,
+};
+use futures::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
 use tempfile::NamedTempFile;
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
 
-use crate::BUCKET;
+use crate::codec::Codec;
+use crate::config::StorageConfig;
+use crate::integrity;
+use crate::shards::ShardMetadata;
+
+/// Where shard objects live, shared by the coordinator and every worker
+#[async_trait]
+pub trait ObjectStore: Send + Sync {
+    /// Upload a local file under `key`, replacing any existing object
+    async fn put(&self, key: &str, path: &Path) -> Result<()>;
+
+    /// Stream an object into `file`, returning the number of bytes written
+    async fn get(&self, key: &str, file: File) -> Result<u64>;
 
-pub fn get_s3_client() -> Client {
-    let key_id = "root".to_string();
-    let secret_key = "changeme".to_string();
+    /// Fetch `len` bytes from `offset` of an object
+    async fn get_range(&self, key: &str, offset: u64, len: u64) -> Result<Vec<u8>>;
 
-    let cred = Credentials::new(key_id, secret_key, None, None, "loaded-from-custom-env");
-    let store_url = "http://localhost:9000";
+    /// Keys of all objects starting with `prefix`
+    async fn list(&self, prefix: &str) -> Result<Vec<String>>;
 
-    let s3_config = aws_sdk_s3::config::Builder::new()
-        .endpoint_url(store_url)
-        .credentials_provider(cred)
-        .region(Region::new("eu-central-1"))
-        .behavior_version_latest()
-        .force_path_style(true)
-        .build();
+    async fn delete(&self, key: &str) -> Result<()>;
 
-    return aws_sdk_s3::Client::from_conf(s3_config);
+    /// Size of an object in bytes
+    async fn head(&self, key: &str) -> Result<u64>;
+
+    /// Location of an object, for log messages
+    fn url(&self, key: &str) -> String;
 }
 
-pub async fn upload_db_to_s3(
-    client: &Client,
-    db_file: &str,
-    key: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let body = tokio::fs::read(db_file).await?;
+pub type Store = Arc<dyn ObjectStore>;
+
+/// Open the configured backend, `s3` or `local`
+pub async fn open_store(config: &StorageConfig) -> Result<Store> {
+    match config.storage.as_deref().unwrap_or("s3") {
+        "s3" => Ok(Arc::new(S3Store::connect(config).await?)),
+        "local" => {
+            let dir = config
+                .storage_dir
+                .clone()
+                .unwrap_or_else(|| std::env::temp_dir().join("shardy-storage"));
 
-    client
-        .put_object()
-        .bucket(BUCKET)
-        .key(key)
-        .body(body.into())
-        .send()
-        .await?;
+            Ok(Arc::new(LocalStore::open(dir)?))
+        }
+        other => bail!("unknown storage backend: {}", other),
+    }
+}
 
-    println!("Database synced to S3: s3://{}/{}", BUCKET, key);
+/// Upload a database file, compressed with `codec` on the way
+pub async fn upload_database(store: &Store, db_file: &Path, key: &str, codec: Codec) -> Result<()> {
+    match codec {
+        Codec::None => store.put(key, db_file).await?,
+        _ => {
+            let encoded = codec.encode(db_file).await?;
+            store.put(key, encoded.path()).await?;
+        }
+    }
+
+    println!("Database synced to {}", store.url(key));
     Ok(())
 }
 
-/// Download SQLite database from S3 to a temporary file
-pub async fn download_database(s3_client: &Client, key: &str) -> Result<NamedTempFile> {
-    // Get object from S3
-    let response = s3_client
-        .get_object()
-        .bucket(BUCKET)
-        .key(key)
-        .send()
-        .await?;
+/// Suffix of the metadata object stored next to every shard object
+pub const SIDECAR_SUFFIX: &str = ".meta.json";
+
+/// Metadata of a shard as uploaded, enough to rebuild its catalog row from the bucket alone
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ShardSidecar {
+    #[serde(flatten)]
+    pub shard: ShardMetadata,
+    /// Tells apart versions of a shard rewritten under a new key
+    pub uploaded_at: String,
+    /// Shards merged into this one by compaction
+    #[serde(default)]
+    pub replaces: Vec<String>,
+}
+
+pub fn sidecar_key(storage_key: &str) -> String {
+    format!("{}{}", storage_key, SIDECAR_SUFFIX)
+}
+
+/// Store the metadata of a shard next to its object, after the object itself is uploaded
+pub async fn upload_sidecar(
+    store: &Store,
+    shard: &ShardMetadata,
+    replaces: &[String],
+) -> Result<()> {
+    let sidecar = ShardSidecar {
+        shard: shard.clone(),
+        uploaded_at: time::UtcDateTime::now().to_string(),
+        replaces: replaces.to_vec(),
+    };
+
+    let file = NamedTempFile::new()?;
+    tokio::fs::write(file.path(), serde_json::to_vec(&sidecar)?).await?;
+
+    store
+        .put(&sidecar_key(&shard.storage_key), file.path())
+        .await
+}
+
+pub async fn download_sidecar(store: &Store, storage_key: &str) -> Result<ShardSidecar> {
+    let key = sidecar_key(storage_key);
+    let size = store.head(&key).await?;
+    let body = store.get_range(&key, 0, size).await?;
 
+    serde_json::from_slice(&body).with_context(|| format!("invalid sidecar {}", key))
+}
+
+/// Download SQLite database to a temporary file
+pub async fn download_database(store: &Store, shard: &ShardMetadata) -> Result<NamedTempFile> {
     // Create temporary file
     let temp_file = NamedTempFile::new()?;
 
-    // Stream data from S3 to temp file
-    let mut stream = response.body.into_async_read();
-    let mut file_handle = File::from_std(temp_file.reopen()?);
-
-    tokio::io::copy(&mut stream, &mut file_handle).await?;
-    file_handle.flush().await?;
+    download_to(store, shard, temp_file.path()).await?;
 
     Ok(temp_file)
 }
+
+fn downloads() -> &'static Mutex<HashMap<String, usize>> {
+    static DOWNLOADS: OnceLock<Mutex<HashMap<String, usize>>> = OnceLock::new();
+
+    DOWNLOADS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// A shard download in progress, listed by `downloading` until dropped
+struct Download {
+    key: String,
+}
+
+impl Download {
+    fn start(key: &str) -> Download {
+        *downloads()
+            .lock()
+            .unwrap()
+            .entry(key.to_owned())
+            .or_insert(0) += 1;
+
+        Download {
+            key: key.to_owned(),
+        }
+    }
+}
+
+impl Drop for Download {
+    fn drop(&mut self) {
+        let mut downloads = downloads().lock().unwrap();
+
+        if let Some(count) = downloads.get_mut(&self.key) {
+            *count -= 1;
+
+            if *count == 0 {
+                downloads.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// Storage keys of the shards this process is downloading right now
+pub fn downloading() -> Vec<String> {
+    downloads().lock().unwrap().keys().cloned().collect()
+}
+
+/// Download a shard into `path`, decoded and checked against its checksum.
+/// Returns the size of the database file.
+pub async fn download_to(store: &Store, shard: &ShardMetadata, path: &Path) -> Result<u64> {
+    let key = &shard.storage_key;
+    let _download = Download::start(key);
+
+    let size = match shard.codec {
+        Codec::None => store.get(key, File::create(path).await?).await?,
+        codec => {
+            let encoded = NamedTempFile::new()?;
+            store.get(key, File::from_std(encoded.reopen()?)).await?;
+
+            codec
+                .decode(encoded.path(), path)
+                .await
+                .map_err(|e| integrity::corrupt_unless_io(key, e, "could not decode"))?
+        }
+    };
+
+    integrity::verify_checksum(key, path, shard.sha256.as_deref()).await?;
+
+    Ok(size)
+}
+
+/// Objects in an S3 compatible bucket, optionally under a key prefix
+pub struct S3Store {
+    client: Client,
+    bucket: String,
+    prefix: String,
+    /// Files larger than one part are uploaded in parts of this size
+    part_size: u64,
+    /// Parts of one upload in flight at once
+    upload_concurrency: usize,
+}
+
+/// Smallest part size S3 accepts, except for the last part
+const MIN_PART_SIZE_MB: u64 = 5;
+
+/// Attempts per part before the whole upload is aborted
+const PART_ATTEMPTS: u32 = 3;
+
+impl S3Store {
+    pub async fn connect(config: &StorageConfig) -> Result<S3Store> {
+        let mut loader = aws_config::defaults(BehaviorVersion::latest());
+
+        if let Some(endpoint) = &config.s3_endpoint {
+            loader = loader.endpoint_url(endpoint);
+        }
+
+        if let Some(region) = &config.s3_region {
+            loader = loader.region(Region::new(region.clone()));
+        }
+
+        match (&config.s3_access_key_id, &config.s3_secret_access_key) {
+            (Some(key_id), Some(secret_key)) => {
+                loader = loader.credentials_provider(Credentials::new(
+                    key_id,
+                    secret_key,
+                    None,
+                    None,
+                    "shardy-config",
+                ));
+            }
+            (None, None) => {}
+            _ => bail!("s3 access key id and secret access key must be set together"),
+        }
+
+        let sdk_config = loader.load().await;
+
+        let s3_config = aws_sdk_s3::config::Builder::from(&sdk_config)
+            .force_path_style(config.s3_path_style.unwrap_or(false))
+            .build();
+
+        // `a/b` and `a/b/` both put objects under `a/b/`
+        let prefix = match config.s3_prefix.as_deref().map(|p| p.trim_matches('/')) {
+            Some(prefix) if !prefix.is_empty() => format!("{}/", prefix),
+            _ => String::new(),
+        };
+
+        let part_size_mb = config.s3_part_size_mb.unwrap_or(16);
+
+        if part_size_mb < MIN_PART_SIZE_MB {
+            bail!("s3 part size must be at least {} MB", MIN_PART_SIZE_MB);
+        }
+
+        let store = S3Store {
+            client: Client::from_conf(s3_config),
+            bucket: config
+                .s3_bucket
+                .clone()
+                .unwrap_or_else(|| "logs".to_owned()),
+            prefix,
+            part_size: part_size_mb * 1024 * 1024,
+            upload_concurrency: config.s3_upload_concurrency.unwrap_or(4).max(1),
+        };
+
+        // Only ask for a location on AWS itself, other implementations have their own idea of regions
+        let location = sdk_config
+            .region()
+            .filter(|_| config.s3_endpoint.is_none())
+            .map(|region| region.to_string());
+
+        store
+            .ensure_bucket(config.s3_create_bucket.unwrap_or(false), location)
+            .await?;
+
+        println!("object storage: {}", store.url(""));
+
+        Ok(store)
+    }
+
+    /// Fail at startup on a missing or inaccessible bucket instead of on the first upload
+    async fn ensure_bucket(&self, create: bool, location: Option<String>) -> Result<()> {
+        let error = match self.client.head_bucket().bucket(&self.bucket).send().await {
+            Ok(_) => return Ok(()),
+            Err(e) => e,
+        };
+
+        let missing = error
+            .as_service_error()
+            .is_some_and(|error| error.is_not_found());
+
+        if !missing || !create {
+            bail!(
+                "bucket {} is not accessible: {}",
+                &self.bucket,
+                DisplayErrorContext(&error)
+            );
+        }
+
+        let mut request = self.client.create_bucket().bucket(&self.bucket);
+
+        if let Some(location) = location.filter(|location| location != "us-east-1") {
+            request = request.create_bucket_configuration(
+                CreateBucketConfiguration::builder()
+                    .location_constraint(BucketLocationConstraint::from(location.as_str()))
+                    .build(),
+            );
+        }
+
+        match request.send().await {
+            Ok(_) => println!("bucket created: {}", &self.bucket),
+            // Another node created it first
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(|error| error.is_bucket_already_owned_by_you()) => {}
+            Err(e) => bail!(
+                "could not create bucket {}: {}",
+                &self.bucket,
+                DisplayErrorContext(&e)
+            ),
+        }
+
+        Ok(())
+    }
+
+    fn object_key(&self, key: &str) -> String {
+        format!("{}{}", &self.prefix, key)
+    }
+
+    /// Upload a large file in parts streamed from disk, aborting the upload on failure
+    /// so no orphaned parts are left behind in the bucket
+    async fn put_multipart(&self, key: &str, path: &Path, size: u64) -> Result<()> {
+        let upload = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await?;
+
+        let upload_id = upload
+            .upload_id()
+            .with_context(|| format!("no upload id for {}", key))?;
+
+        let result = async {
+            let parts = self.upload_parts(key, upload_id, path, size).await?;
+
+            self.client
+                .complete_multipart_upload()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .multipart_upload(
+                    CompletedMultipartUpload::builder()
+                        .set_parts(Some(parts))
+                        .build(),
+                )
+                .send()
+                .await?;
+
+            Ok(())
+        }
+        .await;
+
+        if result.is_err()
+            && let Err(e) = self
+                .client
+                .abort_multipart_upload()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .send()
+                .await
+        {
+            eprintln!(
+                "could not abort upload of {}: {}",
+                key,
+                DisplayErrorContext(&e)
+            );
+        }
+
+        result
+    }
+
+    async fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        path: &Path,
+        size: u64,
+    ) -> Result<Vec<CompletedPart>> {
+        let part_count = size.div_ceil(self.part_size);
+
+        let mut parts: Vec<CompletedPart> = futures::stream::iter(0..part_count)
+            .map(|index| {
+                let offset = index * self.part_size;
+                let len = self.part_size.min(size - offset);
+
+                // Part numbers start at 1
+                self.upload_part(key, upload_id, path, index as i32 + 1, offset, len)
+            })
+            .buffer_unordered(self.upload_concurrency)
+            .try_collect()
+            .await?;
+
+        parts.sort_by_key(|part| part.part_number());
+
+        Ok(parts)
+    }
+
+    /// Upload one part, retrying it alone when it fails
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        path: &Path,
+        part_number: i32,
+        offset: u64,
+        len: u64,
+    ) -> Result<CompletedPart> {
+        let mut attempt = 1;
+
+        loop {
+            let result = async {
+                // Read from disk as the request is sent, a part is never buffered whole
+                let body = ByteStream::read_from()
+                    .path(path)
+                    .offset(offset)
+                    .length(Length::Exact(len))
+                    .build()
+                    .await?;
+
+                let response = self
+                    .client
+                    .upload_part()
+                    .bucket(&self.bucket)
+                    .key(key)
+                    .upload_id(upload_id)
+                    .part_number(part_number)
+                    .content_length(len as i64)
+                    .body(body)
+                    .send()
+                    .await
+                    .map_err(|e| anyhow!("{}", DisplayErrorContext(&e)))?;
+
+                Ok::<_, anyhow::Error>(
+                    CompletedPart::builder()
+                        .part_number(part_number)
+                        .set_e_tag(response.e_tag().map(str::to_owned))
+                        .build(),
+                )
+            }
+            .await;
+
+            match result {
+                Ok(part) => return Ok(part),
+                Err(e) if attempt < PART_ATTEMPTS => {
+                    eprintln!(
+                        "upload of part {} of {} failed, attempt {}: {}",
+                        part_number, key, attempt, e
+                    );
+
+                    tokio::time::sleep(Duration::from_millis(500 << attempt)).await;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    return Err(e.context(format!("upload of part {} of {}", part_number, key)));
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl ObjectStore for S3Store {
+    async fn put(&self, key: &str, path: &Path) -> Result<()> {
+        let key = self.object_key(key);
+        let size = tokio::fs::metadata(path).await?.len();
+
+        if size > self.part_size {
+            return self.put_multipart(&key, path, size).await;
+        }
+
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .body(ByteStream::from_path(path).await?)
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    /// Fails if fewer bytes arrive than S3 announced
+    async fn get(&self, key: &str, mut file: File) -> Result<u64> {
+        let response = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(key))
+            .send()
+            .await?;
+
+        let expected = response.content_length();
+
+        // Stream data from S3 to file
+        let mut stream = response.body.into_async_read();
+
+        let written = tokio::io::copy(&mut stream, &mut file).await?;
+        file.flush().await?;
+
+        if let Some(expected) = expected
+            && written != expected as u64
+        {
+            bail!(
+                "truncated download of {}: {} of {} bytes",
+                key,
+                written,
+                expected
+            );
+        }
+
+        Ok(written)
+    }
+
+    async fn get_range(&self, key: &str, offset: u64, len: u64) -> Result<Vec<u8>> {
+        // An HTTP range can't be empty
+        if len == 0 {
+            return Ok(vec![]);
+        }
+
+        let response = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(key))
+            .range(format!("bytes={}-{}", offset, offset + len - 1))
+            .send()
+            .await?;
+
+        let body = response.body.collect().await?.into_bytes();
+
+        Ok(body.to_vec())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(self.object_key(prefix))
+            .into_paginator()
+            .send();
+
+        let mut keys = vec![];
+
+        while let Some(page) = pages.next().await {
+            keys.extend(
+                page?
+                    .contents()
+                    .iter()
+                    .filter_map(|object| object.key())
+                    .filter_map(|key| key.strip_prefix(&self.prefix))
+                    .map(str::to_owned),
+            );
+        }
+
+        Ok(keys)
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(key))
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn head(&self, key: &str) -> Result<u64> {
+        let response = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(key))
+            .send()
+            .await?;
+
+        let size = response
+            .content_length()
+            .with_context(|| format!("no content length for {}", key))?;
+
+        Ok(size as u64)
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("s3://{}/{}", &self.bucket, self.object_key(key))
+    }
+}
+
+/// Objects as files in a local directory, for single node setups without S3
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+/// Prefix of files being uploaded, never listed as objects
+const LOCAL_UPLOAD_PREFIX: &str = ".upload-";
+
+impl LocalStore {
+    pub fn open(root: PathBuf) -> Result<LocalStore> {
+        std::fs::create_dir_all(&root)?;
+
+        println!("local object storage in {}", root.display());
+
+        Ok(LocalStore { root })
+    }
+
+    /// File of an object, keys may contain `/` but must stay inside the root
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        let relative = Path::new(key);
+
+        if key.is_empty()
+            || !relative
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        {
+            bail!("invalid object key: {}", key);
+        }
+
+        Ok(self.root.join(relative))
+    }
+}
+
+#[async_trait]
+impl ObjectStore for LocalStore {
+    async fn put(&self, key: &str, path: &Path) -> Result<()> {
+        let target = self.path(key)?;
+
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        // Copy next to the target first, readers only ever see complete objects
+        let partial = self
+            .root
+            .join(format!("{}{}", LOCAL_UPLOAD_PREFIX, uuid::Uuid::new_v4()));
+
+        if let Err(e) = tokio::fs::copy(path, &partial).await {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(e.into());
+        }
+
+        tokio::fs::rename(&partial, &target).await?;
+
+        Ok(())
+    }
+
+    async fn get(&self, key: &str, mut file: File) -> Result<u64> {
+        let mut source = File::open(self.path(key)?)
+            .await
+            .with_context(|| format!("no object {}", key))?;
+
+        let written = tokio::io::copy(&mut source, &mut file).await?;
+        file.flush().await?;
+
+        Ok(written)
+    }
+
+    async fn get_range(&self, key: &str, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let path = self.path(key)?;
+
+        tokio::task::spawn_blocking(move || {
+            let mut file = std::fs::File::open(&path)?;
+            file.seek(SeekFrom::Start(offset))?;
+
+            let mut buf = vec![];
+            file.take(len).read_to_end(&mut buf)?;
+
+            Ok(buf)
+        })
+        .await?
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let root = self.root.clone();
+        let prefix = prefix.to_owned();
+
+        tokio::task::spawn_blocking(move || {
+            let mut keys = vec![];
+            let mut dirs = vec![root.clone()];
+
+            while let Some(dir) = dirs.pop() {
+                for entry in std::fs::read_dir(&dir)? {
+                    let entry = entry?;
+                    let path = entry.path();
+
+                    if entry.file_type()?.is_dir() {
+                        dirs.push(path);
+                        continue;
+                    }
+
+                    if entry
+                        .file_name()
+                        .to_string_lossy()
+                        .starts_with(LOCAL_UPLOAD_PREFIX)
+                    {
+                        continue;
+                    }
+
+                    let key = path
+                        .strip_prefix(&root)?
+                        .components()
+                        .map(|component| component.as_os_str().to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join("/");
+
+                    if key.starts_with(&prefix) {
+                        keys.push(key);
+                    }
+                }
+            }
+
+            keys.sort();
+
+            Ok(keys)
+        })
+        .await?
+    }
+
+    /// Deleting a missing object succeeds, like it does on S3
+    async fn delete(&self, key: &str) -> Result<()> {
+        match tokio::fs::remove_file(self.path(key)?).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn head(&self, key: &str) -> Result<u64> {
+        let metadata = tokio::fs::metadata(self.path(key)?)
+            .await
+            .with_context(|| format!("no object {}", key))?;
+
+        Ok(metadata.len())
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("file://{}", self.root.join(key).display())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn local_store() -> (TempDir, LocalStore) {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalStore::open(dir.path().to_owned()).unwrap();
+
+        (dir, store)
+    }
+
+    fn source(contents: &[u8]) -> NamedTempFile {
+        let file = NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), contents).unwrap();
+
+        file
+    }
+
+    #[test]
+    fn keys_stay_inside_the_root() {
+        let (dir, store) = local_store();
+
+        assert_eq!(
+            store.path("logs/shard.db").unwrap(),
+            dir.path().join("logs").join("shard.db")
+        );
+
+        for key in [
+            "",
+            "..",
+            "../escaped.db",
+            "logs/../../escaped.db",
+            "/etc/passwd",
+        ] {
+            assert!(store.path(key).is_err(), "{}", key);
+        }
+    }
+
+    #[tokio::test]
+    async fn objects_round_trip() {
+        let (_dir, store) = local_store();
+
+        let contents: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        store
+            .put("logs/shard.db", source(&contents).path())
+            .await
+            .unwrap();
+
+        assert_eq!(store.head("logs/shard.db").await.unwrap(), 10_000);
+
+        let target = NamedTempFile::new().unwrap();
+        let file = File::create(target.path()).await.unwrap();
+        assert_eq!(store.get("logs/shard.db", file).await.unwrap(), 10_000);
+        assert_eq!(std::fs::read(target.path()).unwrap(), contents);
+
+        assert_eq!(
+            store.get_range("logs/shard.db", 100, 50).await.unwrap(),
+            &contents[100..150]
+        );
+        assert_eq!(
+            store.get_range("logs/shard.db", 9_990, 50).await.unwrap(),
+            &contents[9_990..]
+        );
+
+        store.delete("logs/shard.db").await.unwrap();
+        store.delete("logs/shard.db").await.unwrap();
+
+        assert!(store.head("logs/shard.db").await.is_err());
+        assert!(store.list("").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn listings_skip_partial_uploads() {
+        let (dir, store) = local_store();
+
+        for key in ["logs/a.db", "logs/b.db", "metrics/a.db"] {
+            store.put(key, source(b"shard").path()).await.unwrap();
+        }
+
+        std::fs::write(
+            dir.path()
+                .join(format!("{}interrupted", LOCAL_UPLOAD_PREFIX)),
+            b"partial",
+        )
+        .unwrap();
+
+        assert_eq!(
+            store.list("").await.unwrap(),
+            ["logs/a.db", "logs/b.db", "metrics/a.db"]
+        );
+        assert_eq!(
+            store.list("logs/").await.unwrap(),
+            ["logs/a.db", "logs/b.db"]
+        );
+    }
+
+    #[test]
+    fn downloads_are_listed_until_every_one_is_done() {
+        let key = "logs.downloading.db";
+
+        let first = Download::start(key);
+        let second = Download::start(key);
+
+        drop(first);
+        assert!(downloading().iter().any(|k| k == key));
+
+        drop(second);
+        assert!(!downloading().iter().any(|k| k == key));
+    }
+}
@@ -0,0 +1,60 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+use crate::cluster;
+use crate::state::{ApiState, WorkerQueue};
+
+/// Pending commands after which a worker counts as overloaded and its shards spill over
+const MAX_QUEUED_PER_WORKER: usize = 32;
+
+/// Workers ordered by rendezvous score for a shard, the first one owns it.
+/// Adding or removing a worker only moves the shards it owns or takes over.
+pub fn rank_workers<'a>(
+    storage_key: &str,
+    worker_ids: impl Iterator<Item = &'a String>,
+) -> Vec<&'a String> {
+    let mut ranked: Vec<(u64, &String)> = worker_ids
+        .map(|worker_id| {
+            let mut hasher = DefaultHasher::new();
+            worker_id.hash(&mut hasher);
+            storage_key.hash(&mut hasher);
+
+            (hasher.finish(), worker_id)
+        })
+        .collect();
+
+    ranked.sort_by(|a, b| b.cmp(a));
+
+    ranked.into_iter().map(|(_, worker_id)| worker_id).collect()
+}
+
+/// Worker that should serve a shard, so its cache stays warm, skipping draining workers and
+/// those in `exclude`. Falls through the ranking while workers are overloaded, `None` without workers.
+pub async fn shard_worker(
+    state: &ApiState,
+    storage_key: &str,
+    exclude: &[String],
+) -> Option<(String, WorkerQueue)> {
+    let draining = cluster::draining(state).await;
+    let workers = state.workers.lock().await;
+
+    let mut least_loaded: Option<(usize, &String)> = None;
+
+    for worker_id in rank_workers(
+        storage_key,
+        workers
+            .keys()
+            .filter(|worker_id| !exclude.contains(worker_id) && !draining.contains(*worker_id)),
+    ) {
+        let queued = workers[worker_id].len();
+
+        if queued < MAX_QUEUED_PER_WORKER {
+            return Some((worker_id.clone(), workers[worker_id].clone()));
+        }
+
+        if least_loaded.is_none_or(|(least_queued, _)| queued < least_queued) {
+            least_loaded = Some((queued, worker_id));
+        }
+    }
+
+    least_loaded.map(|(_, worker_id)| (worker_id.clone(), workers[worker_id].clone()))
+}
@@ -0,0 +1,345 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::messages::Message;
+
+/// Version of the coordinator–worker protocol, bumped on every incompatible change to `Message`:
+///
+/// 1. Framed JSON messages after a handshake
+/// 2. MessagePack frames, columnar search results
+/// 3. `Heartbeat`, connections without one for too long are closed
+/// 4. `ShardsHeld`, sent by workers reconnecting with shards not registered yet
+/// 5. `ShardSealed` and `ShardRegistered`
+/// 6. `Drain`
+/// 7. Live deletes leave sealed shards alone and report them in `pending`
+/// 8. `erased` positions in `Log`
+pub const PROTOCOL_VERSION: u32 = 8;
+
+/// Oldest version still spoken. Older workers delete rows from shards they uploaded already,
+/// or write erased messages as empty rows.
+pub const MIN_SUPPORTED_VERSION: u32 = 8;
+
+/// Capability of workers that can read and write MessagePack frames
+pub const MSGPACK: &str = "msgpack";
+
+/// Frames are a 4 byte big endian length followed by the payload
+const MAX_FRAME_LEN: usize = 512 * 1024 * 1024;
+
+/// Limit before the peer is accepted, so unauthenticated connections can't allocate much
+const MAX_HANDSHAKE_FRAME_LEN: usize = 16 * 1024;
+
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// First frame a worker sends. Its shape must never change, so any version can read it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub worker_id: String,
+    /// Optional features, e.g. `ranged_reads`
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+/// Answer of the coordinator to `Hello`, the connection is closed after an error
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Welcome {
+    /// Version both sides speak, the older one of the coordinator and the worker
+    pub protocol_version: u32,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// How messages after the handshake are encoded
+    #[serde(default)]
+    pub encoding: Encoding,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// Encoding of `Message` frames, the handshake itself is always JSON
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    Msgpack,
+}
+
+impl Encoding {
+    pub fn encode(self, message: &Message) -> Result<Vec<u8>> {
+        Ok(match self {
+            Encoding::Json => serde_json::to_vec(message)?,
+            Encoding::Msgpack => rmp_serde::to_vec_named(message)?,
+        })
+    }
+
+    pub fn decode(self, payload: &[u8]) -> Result<Message> {
+        Ok(match self {
+            Encoding::Json => serde_json::from_slice(payload)?,
+            Encoding::Msgpack => rmp_serde::from_slice(payload)?,
+        })
+    }
+}
+
+async fn write_frame<W>(w: &mut W, payload: &[u8]) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    if payload.len() > MAX_FRAME_LEN {
+        bail!("frame of {} bytes is too large", payload.len());
+    }
+
+    w.write_all(&(payload.len() as u32).to_be_bytes()).await?;
+    w.write_all(payload).await?;
+    w.flush().await?;
+
+    Ok(())
+}
+
+/// Read one frame of at most `max_len` bytes, `None` if the connection was closed between frames
+async fn read_frame<R>(r: &mut R, max_len: usize) -> Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut len_bytes = [0u8; 4];
+
+    match r.read_exact(&mut len_bytes).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let len = u32::from_be_bytes(len_bytes) as usize;
+
+    if len > max_len {
+        bail!("frame of {} bytes is too large", len);
+    }
+
+    let mut payload = vec![0; len];
+    r.read_exact(&mut payload)
+        .await
+        .context("connection closed within a frame")?;
+
+    Ok(Some(payload))
+}
+
+async fn write_json<W, T>(w: &mut W, value: &T) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    write_frame(w, &serde_json::to_vec(value)?).await
+}
+
+/// Read a handshake frame
+async fn read_json<R, T>(r: &mut R) -> Result<Option<T>>
+where
+    R: AsyncRead + Unpin,
+    T: for<'de> Deserialize<'de>,
+{
+    match read_frame(r, MAX_HANDSHAKE_FRAME_LEN).await? {
+        Some(payload) => Ok(Some(
+            serde_json::from_slice(&payload).context("could not parse message")?,
+        )),
+        None => Ok(None),
+    }
+}
+
+pub async fn write_message<W>(w: &mut W, message: &Message, encoding: Encoding) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    write_frame(w, &encoding.encode(message)?).await
+}
+
+pub async fn read_message<R>(r: &mut R, encoding: Encoding) -> Result<Option<Message>>
+where
+    R: AsyncRead + Unpin,
+{
+    match read_frame(r, MAX_FRAME_LEN).await? {
+        Some(payload) => Ok(Some(
+            encoding
+                .decode(&payload)
+                .context("could not parse message")?,
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Worker side of the handshake, fails if the coordinator rejects the worker or answers
+/// with a version this worker does not speak
+pub async fn client_handshake<R, W>(r: &mut R, w: &mut W, hello: &Hello) -> Result<Welcome>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    write_json(w, hello).await?;
+
+    let welcome: Welcome = tokio::time::timeout(HANDSHAKE_TIMEOUT, read_json(r))
+        .await
+        .context("handshake timed out")??
+        .context("coordinator closed the connection during the handshake")?;
+
+    if let Some(error) = welcome.error {
+        bail!("coordinator rejected the connection: {}", error);
+    }
+
+    if !(MIN_SUPPORTED_VERSION..=hello.protocol_version).contains(&welcome.protocol_version) {
+        bail!(
+            "coordinator speaks protocol version {}, this worker supports {} to {}",
+            welcome.protocol_version,
+            MIN_SUPPORTED_VERSION,
+            hello.protocol_version
+        );
+    }
+
+    Ok(welcome)
+}
+
+/// Coordinator side of the handshake, answers a worker older than `MIN_SUPPORTED_VERSION`
+/// with an error and fails. Newer workers are answered with `PROTOCOL_VERSION`, they speak
+/// older versions too. MessagePack is used with workers that support it.
+pub async fn server_handshake<R, W>(
+    r: &mut R,
+    w: &mut W,
+    capabilities: &[String],
+) -> Result<(Hello, Welcome)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let hello: Hello = tokio::time::timeout(HANDSHAKE_TIMEOUT, read_json(r))
+        .await
+        .context("handshake timed out")??
+        .context("worker closed the connection during the handshake")?;
+
+    let mut welcome = Welcome {
+        protocol_version: hello.protocol_version.min(PROTOCOL_VERSION),
+        capabilities: capabilities.to_vec(),
+        encoding: Encoding::Json,
+        error: None,
+    };
+
+    if hello.protocol_version < MIN_SUPPORTED_VERSION {
+        let error = format!(
+            "protocol version {} is not supported, the coordinator speaks {} to {}",
+            hello.protocol_version, MIN_SUPPORTED_VERSION, PROTOCOL_VERSION
+        );
+
+        welcome.error = Some(error.clone());
+        write_json(w, &welcome).await?;
+
+        bail!("worker {}: {}", &hello.worker_id, error);
+    }
+
+    if hello
+        .capabilities
+        .iter()
+        .any(|capability| capability == MSGPACK)
+    {
+        welcome.encoding = Encoding::Msgpack;
+    }
+
+    write_json(w, &welcome).await?;
+
+    Ok((hello, welcome))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{DuplexStream, ReadHalf, WriteHalf};
+
+    use super::*;
+
+    type Half = (ReadHalf<DuplexStream>, WriteHalf<DuplexStream>);
+
+    fn connection() -> (Half, Half) {
+        let (worker, coordinator) = tokio::io::duplex(64 * 1024);
+
+        (tokio::io::split(worker), tokio::io::split(coordinator))
+    }
+
+    fn hello(protocol_version: u32, capabilities: &[&str]) -> Hello {
+        Hello {
+            protocol_version,
+            worker_id: "worker-1".to_owned(),
+            capabilities: capabilities.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    /// Both sides of a handshake, run concurrently
+    async fn handshake(hello: Hello) -> (Result<Welcome>, Result<(Hello, Welcome)>, Half, Half) {
+        let ((mut wr, mut ww), (mut cr, mut cw)) = connection();
+
+        let (client, server) = tokio::join!(
+            client_handshake(&mut wr, &mut ww, &hello),
+            server_handshake(&mut cr, &mut cw, &[])
+        );
+
+        (client, server, (wr, ww), (cr, cw))
+    }
+
+    #[tokio::test]
+    async fn unsupported_versions_are_rejected() {
+        let (client, server, _, _) = handshake(hello(MIN_SUPPORTED_VERSION - 1, &[])).await;
+
+        let client = client.unwrap_err().to_string();
+        assert!(client.contains("rejected"), "{}", client);
+        assert!(client.contains("is not supported"), "{}", client);
+
+        assert!(server.is_err());
+    }
+
+    #[tokio::test]
+    async fn newer_workers_speak_the_version_of_the_coordinator() {
+        let (client, server, _, _) = handshake(hello(PROTOCOL_VERSION + 1, &[])).await;
+
+        assert_eq!(client.unwrap().protocol_version, PROTOCOL_VERSION);
+        assert_eq!(server.unwrap().1.protocol_version, PROTOCOL_VERSION);
+
+        let (client, server, _, _) = handshake(hello(MIN_SUPPORTED_VERSION, &[])).await;
+
+        assert_eq!(client.unwrap().protocol_version, MIN_SUPPORTED_VERSION);
+        assert_eq!(server.unwrap().1.protocol_version, MIN_SUPPORTED_VERSION);
+    }
+
+    #[tokio::test]
+    async fn msgpack_is_negotiated_with_workers_that_support_it() {
+        let (client, server, _, _) = handshake(hello(PROTOCOL_VERSION, &[])).await;
+
+        assert_eq!(client.unwrap().encoding, Encoding::Json);
+        assert_eq!(server.unwrap().1.encoding, Encoding::Json);
+
+        let (client, server, (mut wr, _), (_, mut cw)) =
+            handshake(hello(PROTOCOL_VERSION, &[MSGPACK])).await;
+
+        let encoding = client.unwrap().encoding;
+        assert_eq!(encoding, Encoding::Msgpack);
+        assert_eq!(server.unwrap().1.encoding, Encoding::Msgpack);
+
+        write_message(&mut cw, &Message::Drain, encoding)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            read_message(&mut wr, encoding).await.unwrap(),
+            Some(Message::Drain)
+        ));
+    }
+
+    #[tokio::test]
+    async fn large_frames_are_refused_before_the_handshake() {
+        let ((_, mut ww), (mut cr, mut cw)) = connection();
+
+        let len = (MAX_HANDSHAKE_FRAME_LEN + 1) as u32;
+        ww.write_all(&len.to_be_bytes()).await.unwrap();
+
+        let error = server_handshake(&mut cr, &mut cw, &[])
+            .await
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.contains("too large"), "{}", error);
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{Context, Result};
+use sqlx::SqlitePool;
+use sqlx::sqlite::SqliteConnectOptions;
+
+use crate::codec::Codec;
+use crate::gc;
+use crate::integrity::sha256_file;
+use crate::object_storage::{
+    SIDECAR_SUFFIX, ShardSidecar, Store, download_database, download_sidecar,
+};
+use crate::shards::{ShardMetadata, insert_shard, retire_shard};
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Differences between the bucket and the catalog found by `recover`
+#[derive(Debug, Default)]
+pub struct RecoveryReport {
+    /// Objects with no catalog row, registered unless it is a dry run
+    pub recovered: Vec<String>,
+    /// Catalog rows whose object is missing from the bucket, left as they are
+    pub missing_objects: Vec<String>,
+    /// Objects replaced by a rewrite or compaction, scheduled for deletion
+    pub superseded: Vec<String>,
+    /// Objects whose id is registered under another key, left for an operator to resolve
+    pub conflicts: Vec<String>,
+    /// Objects whose metadata could not be read
+    pub unreadable: Vec<String>,
+}
+
+impl RecoveryReport {
+    pub fn print(&self, dry_run: bool) {
+        let action = if dry_run {
+            "would recover"
+        } else {
+            "recovered"
+        };
+
+        for key in &self.recovered {
+            println!("{}: {}", action, key);
+        }
+        for key in &self.superseded {
+            println!("superseded: {}", key);
+        }
+        for conflict in &self.conflicts {
+            println!("conflict: {}", conflict);
+        }
+        for key in &self.missing_objects {
+            println!("missing object: {}", key);
+        }
+        for error in &self.unreadable {
+            println!("unreadable: {}", error);
+        }
+
+        println!(
+            "recovery: {} {}, {} superseded, {} conflict(s), {} missing object(s), {} unreadable",
+            self.recovered.len(),
+            action,
+            self.superseded.len(),
+            self.conflicts.len(),
+            self.missing_objects.len(),
+            self.unreadable.len()
+        );
+    }
+}
+
+/// Compare the bucket with the catalog and register every shard object the catalog lost.
+/// Metadata comes from the sidecar of each object, shards uploaded before sidecars are read whole.
+pub async fn recover(store: &Store, pool: &SqlitePool, dry_run: bool) -> Result<RecoveryReport> {
+    let mut report = RecoveryReport::default();
+
+    let keys = store.list("").await?;
+    let sidecars: HashSet<&str> = keys
+        .iter()
+        .filter_map(|key| key.strip_suffix(SIDECAR_SUFFIX))
+        .collect();
+    let objects: HashSet<&String> = keys
+        .iter()
+        .filter(|key| !key.ends_with(SIDECAR_SUFFIX))
+        .collect();
+
+    println!("recovery: {} object(s) in {}", objects.len(), store.url(""));
+
+    let catalog = sqlx::query_as::<_, ShardMetadata>("SELECT * FROM shards")
+        .fetch_all(pool)
+        .await?;
+    let garbage: Vec<(String,)> = sqlx::query_as("SELECT storage_key FROM garbage")
+        .fetch_all(pool)
+        .await?;
+    let retired: Vec<(String,)> = sqlx::query_as("SELECT id FROM retired_shards")
+        .fetch_all(pool)
+        .await?;
+
+    let catalog_keys: HashSet<&str> = catalog
+        .iter()
+        .map(|shard| shard.storage_key.as_str())
+        .collect();
+    let catalog_ids: HashMap<&str, &str> = catalog
+        .iter()
+        .map(|shard| (shard.id.as_str(), shard.storage_key.as_str()))
+        .collect();
+    let garbage: HashSet<&str> = garbage.iter().map(|(key,)| key.as_str()).collect();
+    let retired: HashSet<&str> = retired.iter().map(|(id,)| id.as_str()).collect();
+
+    // Sidecars of registered objects are read too, they tell which other objects they replace
+    let mut all: Vec<ShardSidecar> = vec![];
+    let mut found: Vec<ShardSidecar> = vec![];
+
+    for key in &objects {
+        // Dropped from the catalog on purpose
+        if garbage.contains(key.as_str()) {
+            continue;
+        }
+
+        let registered = catalog_keys.contains(key.as_str());
+
+        let sidecar = if sidecars.contains(key.as_str()) {
+            download_sidecar(store, key).await
+        } else if registered {
+            continue;
+        } else {
+            inspect(store, key).await
+        };
+
+        match sidecar {
+            Ok(sidecar) if registered => all.push(sidecar),
+            Ok(sidecar) => {
+                all.push(sidecar.clone());
+                found.push(sidecar);
+            }
+            Err(e) if registered => eprintln!("recovery: could not read {}: {:#}", key, e),
+            Err(e) => report.unreadable.push(format!("{}: {:#}", key, e)),
+        }
+    }
+
+    let replaced: HashSet<&str> = all
+        .iter()
+        .flat_map(|sidecar| sidecar.replaces.iter().map(String::as_str))
+        .collect();
+
+    let mut newest: HashMap<&str, &str> = HashMap::new();
+    for sidecar in &all {
+        let entry = newest
+            .entry(sidecar.shard.id.as_str())
+            .or_insert(sidecar.uploaded_at.as_str());
+
+        if sidecar.uploaded_at.as_str() > *entry {
+            *entry = sidecar.uploaded_at.as_str();
+        }
+    }
+
+    let mut recovered = vec![];
+
+    for sidecar in &found {
+        let shard = &sidecar.shard;
+
+        let superseded = replaced.contains(shard.id.as_str())
+            || retired.contains(shard.id.as_str())
+            || newest.get(shard.id.as_str()) != Some(&sidecar.uploaded_at.as_str());
+
+        if superseded {
+            report.superseded.push(shard.storage_key.clone());
+        } else if let Some(registered) = catalog_ids.get(shard.id.as_str()) {
+            report.conflicts.push(format!(
+                "{}: shard {} is registered as {}",
+                &shard.storage_key, &shard.id, registered
+            ));
+        } else {
+            report.recovered.push(shard.storage_key.clone());
+            recovered.push(shard.clone());
+        }
+    }
+
+    report.missing_objects = catalog
+        .iter()
+        .filter(|shard| !objects.contains(&shard.storage_key))
+        .map(|shard| shard.storage_key.clone())
+        .collect();
+
+    if !dry_run {
+        let mut tx = pool.begin().await?;
+
+        for shard in &recovered {
+            insert_shard(&mut *tx, shard).await?;
+        }
+
+        for key in &report.superseded {
+            gc::schedule_deletion(&mut *tx, key, gc::GRACE_PERIOD_SECS).await?;
+        }
+
+        for id in &replaced {
+            retire_shard(&mut *tx, id).await?;
+        }
+
+        tx.commit().await?;
+    }
+
+    Ok(report)
+}
+
+/// Read the metadata of a shard uploaded without a sidecar from the database itself
+async fn inspect(store: &Store, key: &str) -> Result<ShardSidecar> {
+    let magic = store.get_range(key, 0, ZSTD_MAGIC.len() as u64).await?;
+
+    let codec = if magic == ZSTD_MAGIC {
+        Codec::Zstd
+    } else {
+        Codec::None
+    };
+
+    // Keys are `<index>.<minute>.<uuid>.db`, see `shards::storage_key`
+    let parts: Vec<&str> = key.split('.').collect();
+    let (name, id) = match parts.as_slice() {
+        [name, _, id, "db"] => (name.to_string(), id.to_string()),
+        _ => (
+            key.split('.').next().unwrap_or_default().to_owned(),
+            uuid::Uuid::new_v4().to_string(),
+        ),
+    };
+
+    let mut shard = ShardMetadata {
+        name,
+        id,
+        storage_key: key.to_owned(),
+        timestamp: String::new(),
+        end_timestamp: None,
+        size_bytes: 0,
+        row_count: 0,
+        codec,
+        sha256: None,
+    };
+
+    let temp_file = download_database(store, &shard).await?;
+
+    let options = SqliteConnectOptions::new()
+        .filename(temp_file.path())
+        .read_only(true)
+        .immutable(true);
+    let db = SqlitePool::connect_with(options).await?;
+
+    let (start, end, row_count): (Option<String>, Option<String>, i64) =
+        sqlx::query_as("SELECT MIN(timestamp), MAX(timestamp), COUNT(*) FROM logs")
+            .fetch_one(&db)
+            .await?;
+
+    db.close().await;
+
+    shard.timestamp = start.context("shard has no rows")?;
+    shard.end_timestamp = end;
+    shard.row_count = row_count;
+    shard.size_bytes = temp_file.as_file().metadata()?.len() as i64;
+    shard.sha256 = Some(sha256_file(temp_file.path()).await?);
+
+    Ok(ShardSidecar {
+        shard,
+        uploaded_at: String::new(),
+        replaces: vec![],
+    })
+}
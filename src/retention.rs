@@ -0,0 +1,65 @@
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::{
+    gc,
+    indices::list_indices,
+    shards::{ShardMetadata, retire_shard},
+    state::ApiState,
+};
+
+const RETENTION_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+pub async fn start_retention(state: ApiState) -> Result<()> {
+    let mut i = tokio::time::interval(RETENTION_INTERVAL);
+
+    loop {
+        i.tick().await;
+
+        if let Err(e) = expire_shards(&state).await {
+            eprintln!("retention failed: {}", e);
+        }
+    }
+}
+
+async fn expire_shards(state: &ApiState) -> Result<()> {
+    for index in list_indices(&state.master_db).await? {
+        let Some(retention_days) = index.retention_days else {
+            continue;
+        };
+
+        let mut tx = state.master_db.begin().await?;
+
+        let expired = sqlx::query_as::<_, ShardMetadata>(
+            "SELECT * FROM shards WHERE name = ?1 AND COALESCE(end_timestamp, timestamp) < datetime('now', ?2)",
+        )
+        .bind(&index.name)
+        .bind(format!("-{} days", retention_days))
+        .fetch_all(&mut *tx)
+        .await?;
+
+        for shard in &expired {
+            sqlx::query("DELETE FROM shards WHERE id = ?1")
+                .bind(&shard.id)
+                .execute(&mut *tx)
+                .await?;
+
+            retire_shard(&mut *tx, &shard.id).await?;
+            gc::schedule_deletion(&mut *tx, &shard.storage_key, gc::GRACE_PERIOD_SECS).await?;
+        }
+
+        tx.commit().await?;
+
+        if !expired.is_empty() {
+            println!(
+                "expired {} shard(s) of {} older than {} days",
+                expired.len(),
+                &index.name,
+                retention_days
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{Context, Result, bail};
+
+use crate::cluster;
+use crate::placement::rank_workers;
+use crate::state::{ApiState, WorkerQueue};
+
+/// How ingested batches are spread over the workers
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RoutingStrategy {
+    /// The worker with the fewest commands queued on the coordinator plus the work in
+    /// progress it reported in its last heartbeat
+    #[default]
+    LeastLoaded,
+    RoundRobin,
+    /// The worker owning the value of a field of the messages, so they share shards
+    HashByField,
+}
+
+pub struct IngestRouter {
+    strategy: RoutingStrategy,
+    /// Top level field of JSON messages used by `HashByField`
+    field: Option<String>,
+    next: AtomicUsize,
+}
+
+impl IngestRouter {
+    pub fn new(strategy: RoutingStrategy, field: Option<String>) -> Result<IngestRouter> {
+        if strategy == RoutingStrategy::HashByField && field.is_none() {
+            bail!("hash-by-field routing needs --routing-field");
+        }
+
+        Ok(IngestRouter {
+            strategy,
+            field,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Split a request into batches that are each sent to a single worker.
+    /// Only hash-by-field routing splits, by the worker owning each message.
+    pub async fn split(&self, state: &ApiState, logs: Vec<String>) -> Vec<Vec<String>> {
+        if self.strategy != RoutingStrategy::HashByField {
+            return vec![logs];
+        }
+
+        let draining = cluster::draining(state).await;
+        let worker_ids: Vec<String> = state
+            .workers
+            .lock()
+            .await
+            .keys()
+            .filter(|worker_id| !draining.contains(*worker_id))
+            .cloned()
+            .collect();
+
+        let mut batches: HashMap<Option<&String>, Vec<String>> = HashMap::new();
+
+        for log in logs {
+            let owner = rank_workers(&self.key(&log), worker_ids.iter())
+                .first()
+                .copied();
+
+            batches.entry(owner).or_default().push(log);
+        }
+
+        batches.into_values().collect()
+    }
+
+    /// Worker for a batch, with hash-by-field the owner of its first message
+    pub async fn pick(&self, state: &ApiState, logs: &[String]) -> Result<(String, WorkerQueue)> {
+        let draining = cluster::draining(state).await;
+        let backlogs = cluster::backlogs(state).await;
+        let workers = state.workers.lock().await;
+
+        let mut worker_ids: Vec<&String> = workers
+            .keys()
+            .filter(|worker_id| !draining.contains(*worker_id))
+            .collect();
+        worker_ids.sort();
+
+        let worker_id = match self.strategy {
+            RoutingStrategy::LeastLoaded => worker_ids.into_iter().min_by_key(|worker_id| {
+                workers[*worker_id].len() + backlogs.get(*worker_id).copied().unwrap_or(0)
+            }),
+            RoutingStrategy::RoundRobin if worker_ids.is_empty() => None,
+            RoutingStrategy::RoundRobin => {
+                let next = self.next.fetch_add(1, Ordering::Relaxed);
+                Some(worker_ids[next % worker_ids.len()])
+            }
+            RoutingStrategy::HashByField => {
+                let key = logs.first().map(|log| self.key(log)).unwrap_or_default();
+                rank_workers(&key, worker_ids.into_iter()).first().copied()
+            }
+        }
+        .context("no worker connected")?;
+
+        Ok((worker_id.clone(), workers[worker_id].clone()))
+    }
+
+    /// Routing key of a message, empty for messages without the field
+    fn key(&self, log: &str) -> String {
+        let Some(field) = &self.field else {
+            return String::new();
+        };
+
+        let value = serde_json::from_str::<serde_json::Value>(log)
+            .ok()
+            .and_then(|message| message.get(field).cloned());
+
+        match value {
+            Some(serde_json::Value::String(value)) => value,
+            Some(value) => value.to_string(),
+            None => String::new(),
+        }
+    }
+}
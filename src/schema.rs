@@ -23,12 +23,148 @@ pub async fn create_shards_table(pool: &SqlitePool) -> Result<(), sqlx::Error> {
            id TEXT PRIMARY KEY,
            name TEXT NOT NULL,
            storage_key TEXT NOT NULL,
-           timestamp DATETIME NOT NULL
+           timestamp DATETIME NOT NULL,
+           end_timestamp DATETIME,
+           size_bytes INTEGER NOT NULL DEFAULT 0,
+           row_count INTEGER NOT NULL DEFAULT 0,
+           codec TEXT NOT NULL DEFAULT 'none',
+           sha256 TEXT,
+           corruption TEXT
+       )
+       "#,
+    )
+    .execute(pool)
+    .await?;
+
+    add_column_if_missing(pool, "shards", "end_timestamp", "DATETIME").await?;
+    add_column_if_missing(pool, "shards", "size_bytes", "INTEGER NOT NULL DEFAULT 0").await?;
+    add_column_if_missing(pool, "shards", "row_count", "INTEGER NOT NULL DEFAULT 0").await?;
+    add_column_if_missing(pool, "shards", "codec", "TEXT NOT NULL DEFAULT 'none'").await?;
+    add_column_if_missing(pool, "shards", "sha256", "TEXT").await?;
+    add_column_if_missing(pool, "shards", "corruption", "TEXT").await?;
+
+    Ok(())
+}
+
+/// Objects no longer referenced by the catalog, deleted once `delete_after` has passed
+pub async fn create_garbage_table(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+       CREATE TABLE IF NOT EXISTS garbage (
+           storage_key TEXT PRIMARY KEY,
+           delete_after INTEGER NOT NULL
+       )
+       "#,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Ids of shards dropped from the catalog by compaction or retention, kept for good so a
+/// worker sending such a shard again late never registers it a second time
+pub async fn create_retired_shards_table(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+       CREATE TABLE IF NOT EXISTS retired_shards (
+           id TEXT PRIMARY KEY,
+           retired_at INTEGER NOT NULL
+       )
+       "#,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Ingested batches not yet in a registered shard, see `ingest`
+pub async fn create_ingest_buffer_table(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+       CREATE TABLE IF NOT EXISTS ingest_buffer (
+           id TEXT PRIMARY KEY,
+           logs TEXT NOT NULL,
+           received_at INTEGER NOT NULL,
+           dispatched_at INTEGER NOT NULL DEFAULT 0,
+           shard_id TEXT,
+           worker_id TEXT,
+           acked_at INTEGER,
+           disconnected_at INTEGER,
+           erased TEXT NOT NULL DEFAULT '[]'
+       )
+       "#,
+    )
+    .execute(pool)
+    .await?;
+
+    add_column_if_missing(pool, "ingest_buffer", "disconnected_at", "INTEGER").await?;
+    add_column_if_missing(
+        pool,
+        "ingest_buffer",
+        "erased",
+        "TEXT NOT NULL DEFAULT '[]'",
+    )
+    .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS ingest_buffer_shard_id ON ingest_buffer (shard_id)")
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Upgrade tables created by older versions in place
+async fn add_column_if_missing(
+    pool: &SqlitePool,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), sqlx::Error> {
+    let exists = sqlx::query("SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2")
+        .bind(table)
+        .bind(column)
+        .fetch_optional(pool)
+        .await?;
+
+    if exists.is_none() {
+        sqlx::query(&format!(
+            "ALTER TABLE {} ADD COLUMN {} {}",
+            table, column, definition
+        ))
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn create_indices_table(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+       CREATE TABLE IF NOT EXISTS indices (
+           name TEXT PRIMARY KEY,
+           max_bytes INTEGER,
+           max_rows INTEGER,
+           max_age_secs INTEGER,
+           skip_empty BOOLEAN NOT NULL DEFAULT 1,
+           retention_days INTEGER,
+           compression TEXT NOT NULL DEFAULT 'none'
        )
        "#,
     )
     .execute(pool)
     .await?;
 
+    add_column_if_missing(pool, "indices", "retention_days", "INTEGER").await?;
+    add_column_if_missing(
+        pool,
+        "indices",
+        "compression",
+        "TEXT NOT NULL DEFAULT 'none'",
+    )
+    .await?;
+
     Ok(())
 }
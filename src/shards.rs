@@ -1,23 +1,33 @@
-use anyhow::Result;
-use aws_sdk_s3::Client;
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use sqlx::Column;
 use sqlx::Row;
-use sqlx::{FromRow, SqlitePool};
+use sqlx::sqlite::SqliteConnectOptions;
+use sqlx::{Executor, FromRow, Sqlite, SqlitePool};
 use std::collections::HashMap;
+use std::path::Path;
 use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::{Duration, Instant};
 use tempfile::NamedTempFile;
 use time::format_description;
-use tokio::sync::Mutex;
 
+use crate::cache::{CachedShard, ShardCache};
+use crate::cluster;
+use crate::codec::Codec;
 use crate::db::connect_with_options;
+use crate::gc;
+use crate::indices::RotationPolicy;
+use crate::integrity::{self, sha256_file};
 use crate::messages::Message;
+use crate::messages::MessageLiveSearchRequest;
+use crate::messages::MessageLog;
 use crate::messages::MessageSearchRequest;
-use crate::messages::MessageSearchResponse;
-use crate::object_storage::download_database;
-use crate::object_storage::upload_db_to_s3;
+use crate::object_storage::{Store, download_database, upload_database, upload_sidecar};
+use crate::placement;
 use crate::schema::create_logs_table;
+use crate::state::{ApiState, WorkerQueue};
+use crate::vfs::{self, RemoteShard};
 
 pub type QueryResultSet = Vec<HashMap<String, String>>;
 
@@ -25,6 +35,27 @@ pub type QueryResultSet = Vec<HashMap<String, String>>;
 pub struct QueryResult {
     pub items: QueryResultSet,
     pub columns: Vec<String>,
+    /// Storage keys of shards no worker could query, their rows are missing from `items`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failed_shards: Vec<String>,
+}
+
+impl QueryResult {
+    pub fn empty() -> QueryResult {
+        QueryResult {
+            items: vec![],
+            columns: vec![],
+            failed_shards: vec![],
+        }
+    }
+
+    pub fn merge(&mut self, mut other: QueryResult) {
+        self.items.append(&mut other.items);
+        self.columns.append(&mut other.columns);
+        self.failed_shards.append(&mut other.failed_shards);
+        self.columns.sort();
+        self.columns.dedup();
+    }
 }
 
 #[derive(Debug, FromRow, Clone, Deserialize, Serialize)]
@@ -33,27 +64,146 @@ pub struct ShardMetadata {
     pub id: String,
     pub storage_key: String,
     pub timestamp: String,
+    /// Set when the shard is sealed, shards always cover `timestamp..end_timestamp`
+    #[serde(default)]
+    pub end_timestamp: Option<String>,
+    #[serde(default)]
+    pub size_bytes: i64,
+    #[serde(default)]
+    pub row_count: i64,
+    /// How the object is compressed, `size_bytes` is always the size of the database itself
+    #[serde(default)]
+    pub codec: Codec,
+    /// Hex encoded SHA-256 of the database file, recorded on upload
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+/// Rows to erase: `timestamp` within `from..=to` and matching `predicate`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DeleteFilter {
+    pub from: String,
+    pub to: String,
+    pub predicate: String,
+}
+
+impl DeleteFilter {
+    async fn execute<'c, E>(&self, executor: E) -> Result<u64>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let removed = sqlx::query(&format!(
+            "DELETE FROM logs WHERE timestamp >= ?1 AND timestamp <= ?2 AND ({})",
+            self.predicate
+        ))
+        .bind(&self.from)
+        .bind(&self.to)
+        .execute(executor)
+        .await?;
+
+        Ok(removed.rows_affected())
+    }
+
+    async fn count<'c, E>(&self, executor: E) -> Result<u64>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let (matching,): (i64,) = sqlx::query_as(&format!(
+            "SELECT COUNT(*) FROM logs WHERE timestamp >= ?1 AND timestamp <= ?2 AND ({})",
+            self.predicate
+        ))
+        .bind(&self.from)
+        .bind(&self.to)
+        .fetch_one(executor)
+        .await?;
+
+        Ok(matching as u64)
+    }
+
+    /// Positions of the messages of a buffered batch that match, with the columns they
+    /// get once written to a shard
+    pub async fn matching_positions<'c, E>(
+        &self,
+        executor: E,
+        batch: &str,
+        timestamp: &str,
+        logs: &[String],
+    ) -> Result<Vec<usize>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let positions: Vec<(i64,)> = sqlx::query_as(&format!(
+            r#"
+            WITH logs AS (
+                SELECT key AS position, ?3 || '-' || key AS id, ?4 AS timestamp, value AS message
+                FROM json_each(?5)
+            )
+            SELECT position FROM logs WHERE timestamp >= ?1 AND timestamp <= ?2 AND ({})
+            "#,
+            self.predicate
+        ))
+        .bind(&self.from)
+        .bind(&self.to)
+        .bind(batch)
+        .bind(timestamp)
+        .bind(serde_json::to_string(logs)?)
+        .fetch_all(executor)
+        .await?;
+
+        Ok(positions
+            .into_iter()
+            .map(|(position,)| position as usize)
+            .collect())
+    }
+}
+
+/// Index of the shards ingested logs are written to
+pub const DEFAULT_INDEX: &str = "logs";
+
+/// How a worker reads sealed shards from object storage
+#[derive(Clone)]
+pub enum ShardReader {
+    /// Download whole shards into the local cache
+    Cached(Arc<ShardCache>),
+    /// Fetch only the pages a query touches, with ranged requests
+    Ranged(Store),
+}
+
+/// Keeps the database file behind an open shard pool available, only held for its `Drop`
+#[allow(dead_code)]
+enum ShardFile {
+    Cached(CachedShard),
+    Remote(RemoteShard),
+    Downloaded(NamedTempFile),
 }
 
 #[derive(Clone)]
 pub struct Shard {
-    s3client: Client,
+    store: Store,
     metadata: ShardMetadata,
     pool: SqlitePool,
+    /// Read-only connections to the same file, for queries from users
+    reader: SqlitePool,
     shard_filename: String,
+    created: Instant,
+    rows: Arc<AtomicI64>,
+    /// Ids of the shards this one was merged from
+    replaces: Vec<String>,
 }
 
 impl Shard {
-    pub async fn new(s3client: Client) -> Result<Shard> {
+    pub async fn new(store: Store) -> Result<Shard> {
+        Shard::new_in(store, DEFAULT_INDEX).await
+    }
+
+    /// An empty shard of `index`, stored under a key named after it
+    pub async fn new_in(store: Store, index: &str) -> Result<Shard> {
         println!("new shard created");
 
-        let format = format_description::parse("[year]-[month]-[day]_[hour]_[minute]")?;
         let shard_id = uuid::Uuid::new_v4();
         let shard_start_range = time::UtcDateTime::now();
-        let shart_start_range_string = shard_start_range.format(&format)?;
-        let shard_filename = format!("logs.{}.{}.db", &shart_start_range_string, &shard_id);
+        let shard_filename = storage_key(index, &shard_id.to_string())?;
 
-        // TODO: This this should be autorotated periodically somehow
         let mut shard_path = std::env::temp_dir();
         shard_path.push(format!("sqlite_temp_{}.db", uuid::Uuid::new_v4()));
         let shard_url = format!("sqlite:{}", shard_path.display());
@@ -63,35 +213,81 @@ impl Shard {
 
         create_logs_table(&shard_pool).await?;
 
+        // Opened read-only, so no statement of a query can write, whatever pragmas it sets
+        let reader = SqlitePool::connect_lazy_with(
+            SqliteConnectOptions::new()
+                .filename(&shard_path)
+                .read_only(true),
+        );
+
         Ok(Shard {
-            s3client,
+            store,
             metadata: ShardMetadata {
                 timestamp: shard_start_range.to_string(),
                 storage_key: shard_filename.clone(),
                 id: shard_id.to_string(),
-                name: "logs".to_owned(),
+                name: index.to_owned(),
+                end_timestamp: None,
+                size_bytes: 0,
+                row_count: 0,
+                codec: Codec::None,
+                sha256: None,
             },
             pool: shard_pool,
+            reader,
             shard_filename: shard_path.to_str().unwrap().to_owned(),
+            created: Instant::now(),
+            rows: Arc::new(AtomicI64::new(0)),
+            replaces: vec![],
         })
     }
 
-    pub async fn notify_coordinator(&self) -> Result<()> {
-        println!("shard sent, notify_coordinator: {:?}", &self.metadata);
+    pub fn metadata(&self) -> &ShardMetadata {
+        &self.metadata
+    }
 
-        let client = reqwest::Client::new();
+    /// Rows inserted into the local database so far
+    pub fn row_count(&self) -> i64 {
+        self.rows.load(Ordering::Relaxed)
+    }
 
-        client
-            .post("http://localhost:3000/_shard")
-            .body(serde_json::to_string(&self.metadata)?)
-            .header("content-type", "application/json")
-            .send()
+    /// Logical size of the local database, including pages still in the WAL
+    pub async fn size_bytes(&self) -> Result<i64> {
+        let (page_count,): (i64,) = sqlx::query_as("PRAGMA page_count")
+            .fetch_one(&self.pool)
+            .await?;
+        let (page_size,): (i64,) = sqlx::query_as("PRAGMA page_size")
+            .fetch_one(&self.pool)
             .await?;
 
+        Ok(page_count * page_size)
+    }
+
+    pub async fn should_rotate(&self, policy: &RotationPolicy) -> Result<bool> {
+        Ok(policy.should_rotate(
+            self.size_bytes().await?,
+            self.rows.load(Ordering::Relaxed),
+            self.created.elapsed(),
+        ))
+    }
+
+    /// Record the final range, size and row count once no more logs are written,
+    /// and the codec the shard is uploaded with
+    pub async fn seal(&mut self, codec: Codec) -> Result<()> {
+        let (row_count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM logs")
+            .fetch_one(&self.pool)
+            .await?;
+
+        self.metadata.end_timestamp = Some(time::UtcDateTime::now().to_string());
+        self.metadata.size_bytes = self.size_bytes().await?;
+        self.metadata.row_count = row_count;
+        self.metadata.codec = codec;
+
         Ok(())
     }
 
-    pub async fn sync_shard_to_storage(&self) -> Result<(), Box<dyn std::error::Error>> {
+    /// Upload the local database without registering it in the catalog
+    pub async fn upload(&mut self) -> Result<()> {
         println!("wal force: {:?}", &self.metadata.id);
         sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)")
             .execute(&self.pool)
@@ -102,29 +298,218 @@ impl Shard {
 
         println!("wal force done: {:?}", &self.metadata.id);
 
+        self.metadata.sha256 = Some(sha256_file(Path::new(&self.shard_filename)).await?);
+
         println!("upload: {:?}", &self.metadata.id);
 
-        upload_db_to_s3(
-            &self.s3client,
-            &self.shard_filename,
+        upload_database(
+            &self.store,
+            Path::new(&self.shard_filename),
             &self.metadata.storage_key,
+            self.metadata.codec,
         )
         .await?;
 
+        upload_sidecar(&self.store, &self.metadata, &self.replaces).await?;
+
         println!("upload done: {:?}", &self.metadata.id);
 
-        self.notify_coordinator().await?;
+        Ok(())
+    }
+
+    /// Close the local database and delete its files, once the shard is no longer needed
+    pub async fn remove(&self) {
+        self.reader.close().await;
+        self.pool.close().await;
+
+        for suffix in ["", "-wal", "-shm"] {
+            let path = format!("{}{}", &self.shard_filename, suffix);
+
+            match tokio::fs::remove_file(&path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    eprintln!("could not remove {}: {}", &path, e);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Delete matching rows from the local database
+    pub async fn execute_live_delete(&self, filter: &DeleteFilter) -> Result<u64> {
+        let removed = filter.execute(&self.pool).await?;
+
+        self.rows.fetch_sub(removed as i64, Ordering::Relaxed);
+
+        Ok(removed)
+    }
+
+    /// Rows of the local database a filter would delete
+    pub async fn count_matching(&self, filter: &DeleteFilter) -> Result<u64> {
+        filter.count(&self.reader).await
+    }
+
+    /// Delete matching rows from a sealed shard and upload the result under a new key.
+    /// Returns the new metadata, or `None` if nothing matched and the shard is unchanged.
+    pub async fn rewrite(
+        store: &Store,
+        shard: &ShardMetadata,
+        filter: &DeleteFilter,
+    ) -> Result<(Option<ShardMetadata>, u64)> {
+        let temp_file = download_database(store, shard).await?;
+        let pool = connect_with_options(&format!("sqlite:{}", temp_file.path().display())).await?;
+
+        let removed = filter.execute(&pool).await?;
+
+        if removed == 0 {
+            pool.close().await;
+            return Ok((None, 0));
+        }
+
+        sqlx::query("VACUUM").execute(&pool).await?;
+        sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)")
+            .execute(&pool)
+            .await?;
+
+        let (row_count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM logs")
+            .fetch_one(&pool)
+            .await?;
+
+        pool.close().await;
+
+        let mut rewritten = shard.clone();
+        rewritten.storage_key = storage_key(&shard.name, &uuid::Uuid::new_v4().to_string())?;
+        rewritten.size_bytes = temp_file.as_file().metadata()?.len() as i64;
+        rewritten.row_count = row_count;
+        rewritten.sha256 = Some(sha256_file(temp_file.path()).await?);
+
+        upload_database(
+            store,
+            temp_file.path(),
+            &rewritten.storage_key,
+            rewritten.codec,
+        )
+        .await?;
+
+        upload_sidecar(store, &rewritten, &[]).await?;
+
+        Ok((Some(rewritten), removed))
+    }
+
+    /// Merge sealed shards of one index into a new, uploaded shard.
+    /// Registering it in place of the sources is left to the coordinator.
+    pub async fn merge(
+        store: Store,
+        shards: &[ShardMetadata],
+        codec: Codec,
+    ) -> Result<ShardMetadata> {
+        let first = shards.first().context("no shards to merge")?;
+
+        let mut merged = Shard::new_in(store.clone(), &first.name).await?;
+
+        let result = merged.merge_from(shards, codec).await;
+
+        // The local copy is only needed until it is uploaded
+        merged.remove().await;
+
+        result.map(|()| merged.metadata)
+    }
+
+    async fn merge_from(&mut self, shards: &[ShardMetadata], codec: Codec) -> Result<()> {
+        {
+            // ATTACH is per connection, keep the whole merge on one
+            let mut conn = self.pool.acquire().await?;
+
+            for shard in shards {
+                let temp_file = download_database(&self.store, shard).await?;
+
+                sqlx::query("ATTACH DATABASE ?1 AS source")
+                    .bind(temp_file.path().display().to_string())
+                    .execute(&mut *conn)
+                    .await?;
+
+                // Qualified, as `logs` may resolve to the attached table if this connection has
+                // not loaded the schema of the new database yet
+                sqlx::query(
+                    "INSERT OR IGNORE INTO main.logs (id, timestamp, message) SELECT id, timestamp, message FROM source.logs",
+                )
+                .execute(&mut *conn)
+                .await?;
+                sqlx::query("DETACH DATABASE source")
+                    .execute(&mut *conn)
+                    .await?;
+            }
+        }
+
+        sqlx::query("REINDEX").execute(&self.pool).await?;
+        sqlx::query("VACUUM").execute(&self.pool).await?;
+
+        self.seal(codec).await?;
+
+        self.metadata.timestamp = shards
+            .iter()
+            .map(|shard| shard.timestamp.clone())
+            .min()
+            .unwrap_or_default();
+        self.metadata.end_timestamp = shards
+            .iter()
+            .filter_map(|shard| shard.end_timestamp.clone())
+            .max();
+        self.replaces = shards.iter().map(|shard| shard.id.clone()).collect();
+
+        self.upload().await?;
 
         Ok(())
     }
 
-    async fn open_database_from_s3(&self, key: &str) -> Result<(SqlitePool, NamedTempFile)> {
-        // Download database to temp file
-        let temp_file = download_database(&self.s3client, key).await?;
+    async fn open_database_from_s3(
+        reader: &ShardReader,
+        shard: &ShardMetadata,
+    ) -> Result<(SqlitePool, ShardFile)> {
+        // Immutable, so nothing is written next to the database file
+        let options = SqliteConnectOptions::new().read_only(true).immutable(true);
+
+        let (options, file) = match reader {
+            ShardReader::Cached(cache) => {
+                // Local copy of the database, pinned while the pool is open
+                let cached = cache.get(shard).await?;
+
+                (options.filename(cached.path()), ShardFile::Cached(cached))
+            }
+            // Pages can't be read in place from a compressed object, fetch it whole instead
+            ShardReader::Ranged(store) if shard.codec != Codec::None => {
+                let temp_file = download_database(store, shard).await?;
+                integrity::check_database(&shard.storage_key, temp_file.path()).await?;
+
+                (
+                    options.filename(temp_file.path()),
+                    ShardFile::Downloaded(temp_file),
+                )
+            }
+            ShardReader::Ranged(store) => {
+                let remote = vfs::open_remote(store, &shard.storage_key).await?;
+
+                // Pages are not checked against the checksum, at least catch truncated or
+                // replaced objects. Shards registered before sizes were recorded have 0.
+                if shard.size_bytes > 0 && remote.size() != shard.size_bytes as u64 {
+                    return Err(integrity::corrupt(
+                        &shard.storage_key,
+                        format!(
+                            "object has {} bytes, expected {}",
+                            remote.size(),
+                            shard.size_bytes
+                        ),
+                    ));
+                }
+
+                (
+                    options.filename(remote.name()).vfs(vfs::VFS_NAME),
+                    ShardFile::Remote(remote),
+                )
+            }
+        };
 
         // Open SQLite connection
-        let database_url = format!("sqlite:{}", temp_file.path().display());
-        let pool = SqlitePool::connect(&database_url).await?;
+        let pool = SqlitePool::connect_with(options).await?;
 
         // Configure for read operations
         sqlx::query("PRAGMA query_only = ON").execute(&pool).await?;
@@ -135,177 +520,640 @@ impl Shard {
             .execute(&pool)
             .await?; // 256MB mmap
 
-        Ok((pool, temp_file))
+        Ok((pool, file))
     }
 
     pub async fn execute_shard_query(
-        &self,
+        reader: &ShardReader,
         shard: &ShardMetadata,
         query: &str,
     ) -> Result<QueryResult> {
-        let mut result_set: QueryResultSet = vec![];
+        let (pool, _file) = Shard::open_database_from_s3(reader, shard).await?;
 
-        let (pool, _temp_file) = self.open_database_from_s3(&shard.storage_key).await?;
+        let results = run_query(&pool, query).await;
 
-        let rows = sqlx::query(query).fetch_all(&pool).await?;
+        pool.close().await;
 
-        for row in rows {
-            let mut row_as_map: HashMap<String, String> = HashMap::new();
+        results
+    }
 
-            for (i, col) in row.columns().iter().enumerate() {
-                let column_name = col.name();
+    /// Query the local, not yet uploaded database of this shard
+    pub async fn execute_live_query(&self, query: &str) -> Result<QueryResult> {
+        run_query(&self.reader, query).await
+    }
 
-                if let Ok(val) = row.try_get::<i64, _>(i) {
-                    row_as_map.insert(column_name.to_owned(), format!("{}", val));
-                } else if let Ok(val) = row.try_get::<String, _>(i) {
-                    row_as_map.insert(column_name.to_owned(), format!("{}", val));
-                }
+    /// Write a batch in one transaction. Row ids are derived from the batch,
+    /// so a batch delivered twice to the same shard is only stored once.
+    pub async fn insert_logs(&self, batch: &MessageLog) -> Result<()> {
+        let timestamp = time::UtcDateTime::now().to_string();
+
+        let mut tx = self.pool.begin().await?;
+        let mut inserted = 0;
+
+        for (i, message) in batch.logs.iter().enumerate() {
+            if batch.erased.contains(&i) {
+                continue;
             }
 
-            result_set.push(row_as_map);
+            let result = sqlx::query(
+                "INSERT OR IGNORE INTO logs (id, timestamp, message) VALUES (?1, ?2, ?3)",
+            )
+            .bind(format!("{}-{}", &batch.batch, i))
+            .bind(&timestamp)
+            .bind(message)
+            .execute(&mut *tx)
+            .await?;
+
+            inserted += result.rows_affected() as i64;
         }
 
-        pool.close().await;
+        tx.commit().await?;
+
+        self.rows.fetch_add(inserted, Ordering::Relaxed);
+
+        Ok(())
+    }
+}
+
+/// Object key for a new version of a shard, named after its index and the current time
+fn storage_key(name: &str, id: &str) -> Result<String> {
+    let format = format_description::parse("[year]-[month]-[day]_[hour]_[minute]")?;
+    let now = time::UtcDateTime::now().format(&format)?;
+
+    Ok(format!("{}.{}.{}.db", name, now, id))
+}
+
+async fn run_query<'c, E>(executor: E, query: &str) -> Result<QueryResult>
+where
+    E: Executor<'c, Database = Sqlite>,
+{
+    let mut result_set: QueryResultSet = vec![];
+
+    let rows = executor.fetch_all(query).await?;
+
+    for row in rows {
+        let mut row_as_map: HashMap<String, String> = HashMap::new();
 
-        let mut columns: Vec<String> = vec![];
+        for (i, col) in row.columns().iter().enumerate() {
+            let column_name = col.name();
 
-        for key in result_set.first().unwrap_or(&HashMap::new()).keys() {
-            columns.push(key.to_owned());
+            if let Ok(val) = row.try_get::<i64, _>(i) {
+                row_as_map.insert(column_name.to_owned(), val.to_string());
+            } else if let Ok(val) = row.try_get::<String, _>(i) {
+                row_as_map.insert(column_name.to_owned(), val);
+            }
         }
 
-        columns.sort();
+        result_set.push(row_as_map);
+    }
 
-        let results = QueryResult {
-            items: result_set,
-            columns,
-        };
+    let mut columns: Vec<String> = vec![];
 
-        Ok(results)
+    for key in result_set.first().unwrap_or(&HashMap::new()).keys() {
+        columns.push(key.to_owned());
     }
 
-    pub async fn create_log(&self) {
-        let id = uuid::Uuid::new_v4().to_string();
-        let timestamp = time::UtcDateTime::now();
-        let message = format!("http log {}", timestamp);
+    columns.sort();
 
-        if let Err(err) =
-            sqlx::query("INSERT INTO logs (id, timestamp, message) VALUES (?1, ?2, ?3)")
-                .bind(id)
-                .bind(timestamp.to_string())
-                .bind(message)
-                .execute(&self.pool)
-                .await
+    Ok(QueryResult {
+        items: result_set,
+        columns,
+        failed_shards: vec![],
+    })
+}
+
+/// How long a worker gets to answer a shard query before it is sent to another one, on top
+/// of the time to download the shard
+const SHARD_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Slowest download rate expected of a worker fetching a shard it has not cached
+const MIN_DOWNLOAD_BYTES_PER_SEC: u64 = 10 * 1024 * 1024;
+
+/// Retired shards are left out of live queries this long, well beyond the time workers
+/// keep serving shards after their registration
+const RECENTLY_RETIRED_SECS: i64 = 10 * 60;
+
+/// Attempts per shard query, across disconnects, errors and timeouts
+const MAX_SHARD_QUERY_ATTEMPTS: usize = 3;
+
+/// A query against one sealed shard, with the worker it was last sent to
+struct ShardQuery {
+    shard: ShardMetadata,
+    /// Id of the current attempt, responses to earlier attempts are ignored
+    id: String,
+    /// `None` when no worker could take the query
+    worker: Option<(String, WorkerQueue)>,
+    /// Workers that failed or lost this query
+    tried: Vec<String>,
+    attempts: usize,
+    dispatched: Instant,
+}
+
+/// Time a shard query may take on a worker that has to download the shard first
+fn shard_query_timeout(shard: &ShardMetadata) -> Duration {
+    SHARD_QUERY_TIMEOUT
+        + Duration::from_secs(shard.size_bytes.max(0) as u64 / MIN_DOWNLOAD_BYTES_PER_SEC)
+}
+
+/// A query against the rows a worker has not uploaded yet, lost with the worker
+struct LiveQuery {
+    id: String,
+    worker_id: String,
+    queue: WorkerQueue,
+}
+
+/// Send a shard query to the worker that owns the shard, or to another one on retries
+async fn dispatch_shard_query(state: &ApiState, query: &str, shard_query: &mut ShardQuery) {
+    shard_query.id = uuid::Uuid::new_v4().to_string();
+    shard_query.attempts += 1;
+    shard_query.dispatched = Instant::now();
+
+    let command = Message::SearchRequest(MessageSearchRequest {
+        shard: shard_query.shard.clone(),
+        id: shard_query.id.clone(),
+        query: query.to_owned(),
+    });
+
+    // With every worker tried, a transient error may still go away on one of them
+    let worker =
+        match placement::shard_worker(state, &shard_query.shard.storage_key, &shard_query.tried)
+            .await
         {
-            println!("error {}", err);
-        } else {
-            println!("log created");
-        }
-    }
+            Some(worker) => Some(worker),
+            None => placement::shard_worker(state, &shard_query.shard.storage_key, &[]).await,
+        };
+
+    shard_query.worker = match worker {
+        Some((worker_id, queue)) => match queue.send(command) {
+            Ok(()) => Some((worker_id, queue)),
+            Err(e) => {
+                println!("could not send shard query to {}: {}", &worker_id, e);
+                shard_query.tried.push(worker_id);
+                None
+            }
+        },
+        None => None,
+    };
+}
+
+/// Shards workers leave out of live queries: those returned by the catalog, so a shard
+/// registered mid-rotation is counted once, and those retired lately, as a compaction may
+/// have merged a shard workers still serve into one of the catalog
+async fn live_query_exclusions(pool: &SqlitePool, shards: &[ShardMetadata]) -> Result<Vec<String>> {
+    let retired: Vec<(String,)> =
+        sqlx::query_as("SELECT id FROM retired_shards WHERE retired_at >= ?1")
+            .bind(time::UtcDateTime::now().unix_timestamp() - RECENTLY_RETIRED_SECS)
+            .fetch_all(pool)
+            .await?;
+
+    Ok(shards
+        .iter()
+        .map(|shard| shard.id.clone())
+        .chain(retired.into_iter().map(|(id,)| id))
+        .collect())
 }
 
-pub async fn schedule_query(
-    master_db: &SqlitePool,
-    commands: Arc<Mutex<Vec<String>>>,
-    results: Arc<Mutex<HashMap<String, MessageSearchResponse>>>,
-    pattern: &str,
-    query: &str,
-) -> Result<QueryResult> {
+pub async fn schedule_query(state: &ApiState, pattern: &str, query: &str) -> Result<QueryResult> {
     // TODO: make shard time window dynamic (based on query itself partially)
     let shards = sqlx::query_as::<_, ShardMetadata>(
-        "SELECT * FROM shards WHERE name = ?1 AND timestamp > datetime('now', '-60 minutes')",
+        "SELECT * FROM shards WHERE name = ?1 AND COALESCE(end_timestamp, timestamp) > datetime('now', '-60 minutes') AND corruption IS NULL",
     )
     .bind(pattern)
-    .fetch_all(master_db)
+    .fetch_all(&state.master_db)
     .await?;
 
     println!("==============");
     println!("running query: {} on {} shard(s)", query, shards.len());
 
-    let mut queries: Vec<String> = vec![];
+    let mut shard_queries: Vec<ShardQuery> = vec![];
 
-    for shard in shards {
-        let uuid = uuid::Uuid::new_v4();
-        let uuid = uuid.to_string();
+    for shard in &shards {
+        let mut shard_query = ShardQuery {
+            shard: shard.clone(),
+            id: String::new(),
+            worker: None,
+            tried: vec![],
+            attempts: 0,
+            dispatched: Instant::now(),
+        };
 
-        queries.push(uuid.clone());
+        dispatch_shard_query(state, query, &mut shard_query).await;
 
-        commands.lock().await.push(
-            serde_json::to_string(&Message::SearchRequest(MessageSearchRequest {
-                shard: shard.clone(),
-                id: uuid,
-                query: query.to_owned(),
-            }))
-            .unwrap(),
-        );
+        shard_queries.push(shard_query);
     }
 
-    println!("{} queries running", queries.len());
+    // Rows that are not uploaded yet only exist on the workers
+    let exclude = live_query_exclusions(&state.master_db, &shards).await?;
+
+    let mut live_queries: Vec<LiveQuery> = vec![];
+
+    for (worker_id, queue) in state.workers.lock().await.iter() {
+        let uuid = uuid::Uuid::new_v4().to_string();
 
-    let mut combined_results: QueryResultSet = vec![];
-    let mut combined_columns: Vec<String> = vec![];
+        let sent = queue.send(Message::LiveSearchRequest(MessageLiveSearchRequest {
+            id: uuid.clone(),
+            query: query.to_owned(),
+            index: pattern.to_owned(),
+            exclude: exclude.clone(),
+        }));
 
-    let mut count: usize = 0;
+        if let Err(e) = sent {
+            println!("live query failure, worker {}: {}", worker_id, e);
+            continue;
+        }
+
+        live_queries.push(LiveQuery {
+            id: uuid,
+            worker_id: worker_id.clone(),
+            queue: queue.clone(),
+        });
+    }
 
-    let start = time::UtcDateTime::now().unix_timestamp();
+    println!(
+        "{} queries running",
+        shard_queries.len() + live_queries.len()
+    );
 
-    loop {
-        let end = time::UtcDateTime::now().unix_timestamp();
+    let storage_keys: Vec<String> = shards
+        .iter()
+        .map(|shard| shard.storage_key.clone())
+        .collect();
 
-        if end - start == 5 {
-            println!("timeout");
-            break;
+    // Keep compacted or expired objects around until this query is done with them
+    gc::acquire(state, &storage_keys).await;
+
+    let mut combined = QueryResult::empty();
+
+    // Ids of abandoned attempts, whose late responses must not stay in `results`
+    let mut abandoned: Vec<String> = vec![];
+
+    let live_start = Instant::now();
+
+    while !shard_queries.is_empty() || !live_queries.is_empty() {
+        let mut running = vec![];
+
+        for mut shard_query in std::mem::take(&mut shard_queries) {
+            let response = state.results.lock().await.remove(&shard_query.id);
+
+            let error = match (response, &shard_query.worker) {
+                (Some(response), _) => match response.error {
+                    Some(error) => error,
+                    None => {
+                        combined.merge(response.payload);
+                        continue;
+                    }
+                },
+                (None, Some((worker_id, queue)))
+                    if cluster::worker_lost(state, worker_id, queue).await =>
+                {
+                    format!("worker {} disconnected", worker_id)
+                }
+                (None, None) => "no worker available".to_owned(),
+                // A worker still downloading the shard is making progress, another would start over
+                (None, Some((worker_id, _)))
+                    if shard_query.dispatched.elapsed()
+                        >= shard_query_timeout(&shard_query.shard)
+                        && !cluster::downloading(
+                            state,
+                            worker_id,
+                            &shard_query.shard.storage_key,
+                        )
+                        .await =>
+                {
+                    abandoned.push(shard_query.id.clone());
+                    "timed out".to_owned()
+                }
+                (None, _) => {
+                    running.push(shard_query);
+                    continue;
+                }
+            };
+
+            println!(
+                "query failure, shard: {}, attempt {}: {}",
+                &shard_query.shard.id, shard_query.attempts, error
+            );
+
+            if let Some((worker_id, _)) = shard_query.worker.take() {
+                shard_query.tried.push(worker_id);
+            }
+
+            if shard_query.attempts >= MAX_SHARD_QUERY_ATTEMPTS {
+                combined.failed_shards.push(shard_query.shard.storage_key);
+                continue;
+            }
+
+            // Every shard is in object storage, any worker can serve it
+            dispatch_shard_query(state, query, &mut shard_query).await;
+            running.push(shard_query);
         }
 
-        for id in queries.clone() {
-            if let Some(result) = results.lock().await.remove(&id) {
-                combined_results.append(&mut result.payload.items.clone());
-                combined_columns.append(&mut result.payload.columns.clone());
-                count += 1;
+        shard_queries = running;
+
+        let mut running = vec![];
+
+        for live_query in std::mem::take(&mut live_queries) {
+            if let Some(response) = state.results.lock().await.remove(&live_query.id) {
+                combined.merge(response.payload);
+            } else if cluster::worker_lost(state, &live_query.worker_id, &live_query.queue).await {
+                println!(
+                    "live query failure, worker {} disconnected",
+                    &live_query.worker_id
+                );
+            } else if live_start.elapsed() >= SHARD_QUERY_TIMEOUT {
+                println!(
+                    "live query failure, worker {} timed out",
+                    &live_query.worker_id
+                );
+                abandoned.push(live_query.id);
+            } else {
+                running.push(live_query);
             }
         }
 
-        tokio::time::sleep(Duration::from_millis(50)).await;
+        live_queries = running;
 
-        if count != queries.len() {
-            println!("{} queries done", count);
-        } else {
-            break;
+        if !shard_queries.is_empty() || !live_queries.is_empty() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
         }
     }
 
-    combined_columns.dedup();
-    combined_columns.sort();
+    gc::release(state, &storage_keys).await;
 
-    println!("all {} queries done", count);
+    {
+        let mut results = state.results.lock().await;
 
-    println!("results: {}", combined_results.len());
+        for id in &abandoned {
+            results.remove(id);
+        }
+    }
+
+    if !combined.failed_shards.is_empty() {
+        println!(
+            "{} shard(s) failed after {} attempts",
+            combined.failed_shards.len(),
+            MAX_SHARD_QUERY_ATTEMPTS
+        );
+    }
+
+    println!("results: {}", combined.items.len());
 
     println!("==============");
 
-    Ok(QueryResult {
-        columns: combined_columns,
-        items: combined_results,
-    })
+    Ok(combined)
 }
 
+/// Register a sealed shard. Workers send it until they are acknowledged, so a shard that is
+/// registered already, or was registered and then retired, is left alone.
 pub async fn store_shard(pool: &SqlitePool, metadata: &ShardMetadata) -> Result<()> {
-    let exists = sqlx::query("SELECT * FROM shards WHERE id = ?1")
-        .bind(&metadata.id)
-        .fetch_optional(pool)
-        .await?;
+    let mut tx = pool.begin().await?;
+
+    let known = sqlx::query(
+        "SELECT 1 FROM shards WHERE id = ?1 UNION ALL SELECT 1 FROM retired_shards WHERE id = ?1",
+    )
+    .bind(&metadata.id)
+    .fetch_optional(&mut *tx)
+    .await?;
 
-    // If shard already exists, do nothing
-    if exists.is_some() {
+    if known.is_some() {
         return Ok(());
     }
 
-    sqlx::query("INSERT INTO shards (id, name, storage_key, timestamp) VALUES(?1, ?2, ?3, ?4)")
-        .bind(&metadata.id)
-        .bind(&metadata.name)
-        .bind(&metadata.storage_key)
-        .bind(&metadata.timestamp)
+    insert_shard(&mut *tx, metadata).await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// A shard left out of queries after a worker found it corrupt
+#[derive(Debug, FromRow, Serialize)]
+pub struct UnhealthyShard {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub shard: ShardMetadata,
+    pub corruption: String,
+}
+
+pub async fn mark_corrupt(pool: &SqlitePool, storage_key: &str, reason: &str) -> Result<()> {
+    sqlx::query("UPDATE shards SET corruption = ?2 WHERE storage_key = ?1")
+        .bind(storage_key)
+        .bind(reason)
         .execute(pool)
         .await?;
 
     Ok(())
 }
+
+pub async fn list_unhealthy(pool: &SqlitePool) -> Result<Vec<UnhealthyShard>> {
+    let shards = sqlx::query_as::<_, UnhealthyShard>(
+        "SELECT * FROM shards WHERE corruption IS NOT NULL ORDER BY timestamp",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(shards)
+}
+
+/// Record that a shard left the catalog for good, in the transaction that removes it
+pub async fn retire_shard<'c, E>(executor: E, id: &str) -> Result<()>
+where
+    E: Executor<'c, Database = Sqlite>,
+{
+    sqlx::query("INSERT OR IGNORE INTO retired_shards (id, retired_at) VALUES (?1, ?2)")
+        .bind(id)
+        .bind(time::UtcDateTime::now().unix_timestamp())
+        .execute(executor)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn insert_shard<'c, E>(executor: E, metadata: &ShardMetadata) -> Result<()>
+where
+    E: Executor<'c, Database = Sqlite>,
+{
+    sqlx::query(
+        r#"
+        INSERT INTO shards (id, name, storage_key, timestamp, end_timestamp, size_bytes, row_count, codec, sha256)
+        VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+        "#,
+    )
+    .bind(&metadata.id)
+    .bind(&metadata.name)
+    .bind(&metadata.storage_key)
+    .bind(&metadata.timestamp)
+    .bind(&metadata.end_timestamp)
+    .bind(metadata.size_bytes)
+    .bind(metadata.row_count)
+    .bind(metadata.codec)
+    .bind(&metadata.sha256)
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::NamedTempFile;
+
+    use super::*;
+    use crate::db::connect_with_options;
+    use crate::object_storage::{LocalStore, download_sidecar};
+    use crate::schema::{create_retired_shards_table, create_shards_table};
+
+    async fn master_db() -> (NamedTempFile, SqlitePool) {
+        let file = NamedTempFile::new().unwrap();
+        let pool = connect_with_options(&file.path().display().to_string())
+            .await
+            .unwrap();
+
+        create_shards_table(&pool).await.unwrap();
+        create_retired_shards_table(&pool).await.unwrap();
+
+        (file, pool)
+    }
+
+    fn sealed(id: &str) -> ShardMetadata {
+        ShardMetadata {
+            name: DEFAULT_INDEX.to_owned(),
+            id: id.to_owned(),
+            storage_key: format!("logs.{}.db", id),
+            timestamp: "2025-01-01 00:00:00".to_owned(),
+            end_timestamp: Some("2025-01-01 01:00:00".to_owned()),
+            size_bytes: 4096,
+            row_count: 1,
+            codec: Codec::None,
+            sha256: None,
+        }
+    }
+
+    async fn registered(pool: &SqlitePool) -> Vec<String> {
+        sqlx::query_as::<_, (String,)>("SELECT id FROM shards ORDER BY id")
+            .fetch_all(pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(id,)| id)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn retired_shards_are_never_registered_again() {
+        let (_file, pool) = master_db().await;
+
+        store_shard(&pool, &sealed("a")).await.unwrap();
+        store_shard(&pool, &sealed("b")).await.unwrap();
+        store_shard(&pool, &sealed("b")).await.unwrap();
+
+        assert_eq!(registered(&pool).await, ["a", "b"]);
+
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query("DELETE FROM shards WHERE id = 'a'")
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        retire_shard(&mut *tx, "a").await.unwrap();
+        tx.commit().await.unwrap();
+
+        // Sent again by a worker that was away longer than the grace period of the object
+        store_shard(&pool, &sealed("a")).await.unwrap();
+
+        assert_eq!(registered(&pool).await, ["b"]);
+    }
+
+    #[tokio::test]
+    async fn live_queries_leave_out_shards_compacted_in_their_grace_period() {
+        let (_file, pool) = master_db().await;
+
+        store_shard(&pool, &sealed("a")).await.unwrap();
+        store_shard(&pool, &sealed("b")).await.unwrap();
+
+        // Workers still serve `a` and `b` for a while after their registration
+        let mut tx = pool.begin().await.unwrap();
+        for id in ["a", "b"] {
+            sqlx::query("DELETE FROM shards WHERE id = ?1")
+                .bind(id)
+                .execute(&mut *tx)
+                .await
+                .unwrap();
+            retire_shard(&mut *tx, id).await.unwrap();
+        }
+        insert_shard(&mut *tx, &sealed("merged")).await.unwrap();
+        tx.commit().await.unwrap();
+
+        let catalog = sqlx::query_as::<_, ShardMetadata>("SELECT * FROM shards")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+
+        let mut exclude = live_query_exclusions(&pool, &catalog).await.unwrap();
+        exclude.sort();
+
+        assert_eq!(exclude, ["a", "b", "merged"]);
+
+        sqlx::query("UPDATE retired_shards SET retired_at = retired_at - ?1")
+            .bind(RECENTLY_RETIRED_SECS + 1)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            live_query_exclusions(&pool, &catalog).await.unwrap(),
+            ["merged"]
+        );
+    }
+
+    /// An uploaded shard of `index` with `rows` rows
+    async fn uploaded(store: &Store, index: &str, rows: usize) -> ShardMetadata {
+        let mut shard = Shard::new_in(store.clone(), index).await.unwrap();
+
+        shard
+            .insert_logs(&MessageLog {
+                batch: uuid::Uuid::new_v4().to_string(),
+                logs: (0..rows).map(|i| format!("message {}", i)).collect(),
+                erased: vec![],
+            })
+            .await
+            .unwrap();
+        shard.seal(Codec::None).await.unwrap();
+        shard.upload().await.unwrap();
+        shard.remove().await;
+
+        shard.metadata
+    }
+
+    #[tokio::test]
+    async fn compaction_keeps_the_index_of_its_shards() {
+        let dir = tempfile::tempdir().unwrap();
+        let store: Store = Arc::new(LocalStore::open(dir.path().to_owned()).unwrap());
+
+        let shards = [
+            uploaded(&store, "metrics", 2).await,
+            uploaded(&store, "metrics", 3).await,
+        ];
+
+        let merged = Shard::merge(store.clone(), &shards, Codec::None)
+            .await
+            .unwrap();
+
+        assert_eq!(merged.name, "metrics");
+        assert!(
+            merged.storage_key.starts_with("metrics."),
+            "{}",
+            merged.storage_key
+        );
+        assert_eq!(merged.row_count, 5);
+
+        // Recovery rebuilds the catalog from the sidecar
+        let sidecar = download_sidecar(&store, &merged.storage_key).await.unwrap();
+
+        assert_eq!(sidecar.shard.name, "metrics");
+        assert_eq!(sidecar.shard.storage_key, merged.storage_key);
+        assert_eq!(
+            sidecar.replaces,
+            [shards[0].id.clone(), shards[1].id.clone()]
+        );
+    }
+}
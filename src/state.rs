@@ -1,14 +1,73 @@
 use std::{collections::HashMap, sync::Arc};
 
+use anyhow::{Result, bail};
 use sqlx::SqlitePool;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, mpsc};
 
-use crate::messages::MessageSearchResponse;
+use crate::cluster::WorkerStatus;
+use crate::messages::{Message, MessageSearchResponse};
+use crate::object_storage::Store;
+use crate::routing::IngestRouter;
+
+/// Commands a worker connection holds before senders are turned away
+pub const WORKER_QUEUE_CAPACITY: usize = 1024;
+
+/// Outbound commands addressed to a single worker connection, written in order
+#[derive(Clone, Debug)]
+pub struct WorkerQueue {
+    tx: mpsc::Sender<Message>,
+}
+
+impl WorkerQueue {
+    /// A queue and the receiver its connection writes from
+    pub fn new() -> (WorkerQueue, mpsc::Receiver<Message>) {
+        let (tx, rx) = mpsc::channel(WORKER_QUEUE_CAPACITY);
+
+        (WorkerQueue { tx }, rx)
+    }
+
+    /// Queue a command, fails instead of waiting when the worker is far behind or gone
+    pub fn send(&self, message: Message) -> Result<()> {
+        match self.tx.try_send(message) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(_)) => bail!("worker queue is full"),
+            Err(mpsc::error::TrySendError::Closed(_)) => bail!("worker disconnected"),
+        }
+    }
+
+    /// Commands queued but not written to the worker yet
+    pub fn len(&self) -> usize {
+        self.tx.max_capacity() - self.tx.capacity()
+    }
+
+    /// Whether both belong to the same connection
+    pub fn same(&self, other: &WorkerQueue) -> bool {
+        self.tx.same_channel(&other.tx)
+    }
+}
 
 #[derive(Clone)]
 pub struct ApiState {
     pub master_db: SqlitePool,
-    pub client: aws_sdk_s3::Client,
-    pub commands: Arc<Mutex<Vec<String>>>,
+    pub store: Store,
+    pub workers: Arc<Mutex<HashMap<String, WorkerQueue>>>,
+    /// Connected and recently lost workers, see `cluster::list`
+    pub cluster: Arc<Mutex<HashMap<String, WorkerStatus>>>,
     pub results: Arc<Mutex<HashMap<String, MessageSearchResponse>>>,
+    /// Worker responses to background jobs by job id, `None` until they arrive
+    pub jobs: Arc<Mutex<HashMap<String, Option<Message>>>>,
+    /// Running queries per storage key, see `gc::acquire`
+    pub shards_in_use: Arc<Mutex<HashMap<String, usize>>>,
+    pub ingest_router: Arc<IngestRouter>,
+}
+
+impl ApiState {
+    /// Queue a message for every connected worker
+    pub async fn broadcast(&self, message: &Message) {
+        for (worker_id, queue) in self.workers.lock().await.iter() {
+            if let Err(e) = queue.send(message.clone()) {
+                eprintln!("could not send to worker {}: {}", worker_id, e);
+            }
+        }
+    }
 }
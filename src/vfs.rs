@@ -0,0 +1,731 @@
+//! Read-only SQLite VFS serving shard pages with ranged object storage requests,
+//! so a selective query only fetches the pages it touches instead of the whole shard.
+
+use std::{
+    collections::HashMap,
+    ffi::{CStr, CString, c_char, c_int, c_void},
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use anyhow::{Result, anyhow};
+use futures::future::BoxFuture;
+use libsqlite3_sys as ffi;
+use tokio::runtime::Handle;
+
+use crate::object_storage::Store;
+
+pub const VFS_NAME: &str = "shardy_range";
+
+/// Every file of this VFS lives under this prefix, anything else goes to the default VFS
+const PATH_PREFIX: &str = "/shardy_range/";
+
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Page cache per open shard, 64 MiB with 64 KiB chunks
+const MAX_CACHED_CHUNKS: usize = 1024;
+
+type FetchRange = Box<dyn Fn(u64, u64) -> BoxFuture<'static, Result<Vec<u8>>> + Send + Sync>;
+
+#[derive(Default)]
+struct ChunkCache {
+    chunks: HashMap<u64, (u64, Arc<Vec<u8>>)>,
+    clock: u64,
+}
+
+struct RemoteObject {
+    fetch: FetchRange,
+    size: u64,
+    runtime: Handle,
+    chunks: Mutex<ChunkCache>,
+    fetched_bytes: AtomicU64,
+}
+
+impl RemoteObject {
+    fn chunk(&self, index: u64) -> Result<Arc<Vec<u8>>> {
+        {
+            let mut cache = self.chunks.lock().unwrap();
+            cache.clock += 1;
+
+            let clock = cache.clock;
+
+            if let Some((last_used, chunk)) = cache.chunks.get_mut(&index) {
+                *last_used = clock;
+                return Ok(chunk.clone());
+            }
+        }
+
+        let start = index * CHUNK_SIZE;
+        let len = CHUNK_SIZE.min(self.size - start);
+
+        // SQLite calls the VFS from the connection thread of sqlx, outside of the runtime
+        let chunk = Arc::new(self.runtime.block_on((self.fetch)(start, len))?);
+
+        self.fetched_bytes
+            .fetch_add(chunk.len() as u64, Ordering::Relaxed);
+
+        let mut cache = self.chunks.lock().unwrap();
+
+        if cache.chunks.len() >= MAX_CACHED_CHUNKS
+            && let Some(oldest) = cache
+                .chunks
+                .iter()
+                .min_by_key(|(_, (last_used, _))| *last_used)
+                .map(|(index, _)| *index)
+        {
+            cache.chunks.remove(&oldest);
+        }
+
+        let clock = cache.clock;
+        cache.chunks.insert(index, (clock, chunk.clone()));
+
+        Ok(chunk)
+    }
+
+    /// Fill `out` from `offset`, returns the number of bytes available
+    fn read(&self, out: &mut [u8], offset: u64) -> Result<usize> {
+        if offset >= self.size {
+            return Ok(0);
+        }
+
+        let end = (offset + out.len() as u64).min(self.size);
+        let mut pos = offset;
+
+        while pos < end {
+            let index = pos / CHUNK_SIZE;
+            let chunk = self.chunk(index)?;
+
+            let from = (pos - index * CHUNK_SIZE) as usize;
+
+            if from >= chunk.len() {
+                break;
+            }
+
+            let n = (chunk.len() - from).min((end - pos) as usize);
+            let at = (pos - offset) as usize;
+
+            out[at..at + n].copy_from_slice(&chunk[from..from + n]);
+            pos += n as u64;
+        }
+
+        // Shards are uploaded in WAL mode, report the rollback journal format
+        // so SQLite does not look for a WAL index next to a remote file
+        for header_byte in [18, 19] {
+            if (offset..pos).contains(&header_byte) {
+                out[(header_byte - offset) as usize] = 1;
+            }
+        }
+
+        Ok((pos - offset) as usize)
+    }
+}
+
+fn objects() -> &'static Mutex<HashMap<String, Arc<RemoteObject>>> {
+    static OBJECTS: OnceLock<Mutex<HashMap<String, Arc<RemoteObject>>>> = OnceLock::new();
+
+    OBJECTS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// A shard object readable through the VFS under `name`, until dropped
+pub struct RemoteShard {
+    name: String,
+    key: String,
+    object: Arc<RemoteObject>,
+}
+
+impl RemoteShard {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Size of the object in the store
+    pub fn size(&self) -> u64 {
+        self.object.size
+    }
+}
+
+impl Drop for RemoteShard {
+    fn drop(&mut self) {
+        objects().lock().unwrap().remove(&self.name);
+
+        println!(
+            "ranged reads: fetched {} of {} bytes of {}",
+            self.object.fetched_bytes.load(Ordering::Relaxed),
+            self.object.size,
+            &self.key
+        );
+    }
+}
+
+/// Make a shard object readable through the VFS without downloading it
+pub async fn open_remote(store: &Store, key: &str) -> Result<RemoteShard> {
+    let size = store.head(key).await?;
+
+    let store = store.clone();
+    let fetch_key = key.to_owned();
+
+    let fetch: FetchRange = Box::new(move |offset, len| {
+        let store = store.clone();
+        let key = fetch_key.clone();
+
+        Box::pin(async move { store.get_range(&key, offset, len).await })
+    });
+
+    register(key, fetch, size)
+}
+
+fn register(key: &str, fetch: FetchRange, size: u64) -> Result<RemoteShard> {
+    register_vfs()?;
+
+    let name = format!("{}{}.db", PATH_PREFIX, uuid::Uuid::new_v4());
+
+    let object = Arc::new(RemoteObject {
+        fetch,
+        size,
+        runtime: Handle::current(),
+        chunks: Mutex::new(ChunkCache::default()),
+        fetched_bytes: AtomicU64::new(0),
+    });
+
+    objects()
+        .lock()
+        .unwrap()
+        .insert(name.clone(), object.clone());
+
+    Ok(RemoteShard {
+        name,
+        key: key.to_owned(),
+        object,
+    })
+}
+
+fn register_vfs() -> Result<()> {
+    static REGISTERED: OnceLock<Result<(), String>> = OnceLock::new();
+
+    REGISTERED
+        .get_or_init(|| unsafe {
+            let default = ffi::sqlite3_vfs_find(std::ptr::null());
+
+            if default.is_null() {
+                return Err("no default SQLite VFS".to_owned());
+            }
+
+            let vfs = Box::new(ffi::sqlite3_vfs {
+                iVersion: 1,
+                szOsFile: (std::mem::size_of::<RemoteFile>() as c_int).max((*default).szOsFile),
+                mxPathname: (*default).mxPathname,
+                pNext: std::ptr::null_mut(),
+                zName: CString::new(VFS_NAME).unwrap().into_raw(),
+                pAppData: default as *mut c_void,
+                xOpen: Some(x_open),
+                xDelete: Some(x_delete),
+                xAccess: Some(x_access),
+                xFullPathname: Some(x_full_pathname),
+                xDlOpen: Some(x_dl_open),
+                xDlError: Some(x_dl_error),
+                xDlSym: Some(x_dl_sym),
+                xDlClose: Some(x_dl_close),
+                xRandomness: Some(x_randomness),
+                xSleep: Some(x_sleep),
+                xCurrentTime: Some(x_current_time),
+                xGetLastError: Some(x_get_last_error),
+                xCurrentTimeInt64: None,
+                xSetSystemCall: None,
+                xGetSystemCall: None,
+                xNextSystemCall: None,
+            });
+
+            match ffi::sqlite3_vfs_register(Box::into_raw(vfs), 0) {
+                ffi::SQLITE_OK => Ok(()),
+                rc => Err(format!("could not register SQLite VFS: {}", rc)),
+            }
+        })
+        .clone()
+        .map_err(|e| anyhow!(e))
+}
+
+#[repr(C)]
+struct RemoteFile {
+    base: ffi::sqlite3_file,
+    object: *const RemoteObject,
+}
+
+static IO_METHODS: ffi::sqlite3_io_methods = ffi::sqlite3_io_methods {
+    iVersion: 1,
+    xClose: Some(x_close),
+    xRead: Some(x_read),
+    xWrite: Some(x_write),
+    xTruncate: Some(x_truncate),
+    xSync: Some(x_sync),
+    xFileSize: Some(x_file_size),
+    xLock: Some(x_lock),
+    xUnlock: Some(x_lock),
+    xCheckReservedLock: Some(x_check_reserved_lock),
+    xFileControl: Some(x_file_control),
+    xSectorSize: Some(x_sector_size),
+    xDeviceCharacteristics: Some(x_device_characteristics),
+    xShmMap: None,
+    xShmLock: None,
+    xShmBarrier: None,
+    xShmUnmap: None,
+    xFetch: None,
+    xUnfetch: None,
+};
+
+unsafe fn default_vfs(vfs: *mut ffi::sqlite3_vfs) -> *mut ffi::sqlite3_vfs {
+    unsafe { (*vfs).pAppData as *mut ffi::sqlite3_vfs }
+}
+
+unsafe fn is_remote(name: *const c_char) -> bool {
+    !name.is_null()
+        && unsafe { CStr::from_ptr(name) }
+            .to_bytes()
+            .starts_with(PATH_PREFIX.as_bytes())
+}
+
+unsafe fn lookup(name: *const c_char) -> Option<Arc<RemoteObject>> {
+    let name = unsafe { CStr::from_ptr(name) }.to_str().ok()?;
+
+    objects().lock().unwrap().get(name).cloned()
+}
+
+unsafe extern "C" fn x_open(
+    vfs: *mut ffi::sqlite3_vfs,
+    name: *const c_char,
+    file: *mut ffi::sqlite3_file,
+    flags: c_int,
+    out_flags: *mut c_int,
+) -> c_int {
+    unsafe {
+        // Temporary files for sorting and the like stay local
+        if !is_remote(name) {
+            let default = default_vfs(vfs);
+            return (*default).xOpen.unwrap()(default, name, file, flags, out_flags);
+        }
+
+        let object = match lookup(name) {
+            Some(object) if flags & ffi::SQLITE_OPEN_MAIN_DB != 0 => object,
+            _ => return ffi::SQLITE_CANTOPEN,
+        };
+
+        std::ptr::write(
+            file as *mut RemoteFile,
+            RemoteFile {
+                base: ffi::sqlite3_file {
+                    pMethods: &IO_METHODS,
+                },
+                object: Arc::into_raw(object),
+            },
+        );
+
+        if !out_flags.is_null() {
+            *out_flags = ffi::SQLITE_OPEN_READONLY | ffi::SQLITE_OPEN_MAIN_DB;
+        }
+
+        ffi::SQLITE_OK
+    }
+}
+
+unsafe extern "C" fn x_delete(
+    vfs: *mut ffi::sqlite3_vfs,
+    name: *const c_char,
+    sync_dir: c_int,
+) -> c_int {
+    unsafe {
+        if is_remote(name) {
+            return ffi::SQLITE_READONLY;
+        }
+
+        let default = default_vfs(vfs);
+        (*default).xDelete.unwrap()(default, name, sync_dir)
+    }
+}
+
+unsafe extern "C" fn x_access(
+    vfs: *mut ffi::sqlite3_vfs,
+    name: *const c_char,
+    flags: c_int,
+    out: *mut c_int,
+) -> c_int {
+    unsafe {
+        if is_remote(name) {
+            // Journals and WAL files never exist for remote shards
+            *out = (flags != ffi::SQLITE_ACCESS_READWRITE && lookup(name).is_some()) as c_int;
+            return ffi::SQLITE_OK;
+        }
+
+        let default = default_vfs(vfs);
+        (*default).xAccess.unwrap()(default, name, flags, out)
+    }
+}
+
+unsafe extern "C" fn x_full_pathname(
+    vfs: *mut ffi::sqlite3_vfs,
+    name: *const c_char,
+    n_out: c_int,
+    out: *mut c_char,
+) -> c_int {
+    unsafe {
+        if is_remote(name) {
+            let name = CStr::from_ptr(name).to_bytes_with_nul();
+
+            if name.len() > n_out as usize {
+                return ffi::SQLITE_CANTOPEN;
+            }
+
+            std::ptr::copy_nonoverlapping(name.as_ptr() as *const c_char, out, name.len());
+            return ffi::SQLITE_OK;
+        }
+
+        let default = default_vfs(vfs);
+        (*default).xFullPathname.unwrap()(default, name, n_out, out)
+    }
+}
+
+unsafe extern "C" fn x_dl_open(vfs: *mut ffi::sqlite3_vfs, name: *const c_char) -> *mut c_void {
+    unsafe {
+        let default = default_vfs(vfs);
+        (*default).xDlOpen.unwrap()(default, name)
+    }
+}
+
+unsafe extern "C" fn x_dl_error(vfs: *mut ffi::sqlite3_vfs, n: c_int, out: *mut c_char) {
+    unsafe {
+        let default = default_vfs(vfs);
+        (*default).xDlError.unwrap()(default, n, out)
+    }
+}
+
+type DlSym = unsafe extern "C" fn(*mut ffi::sqlite3_vfs, *mut c_void, *const c_char);
+
+unsafe extern "C" fn x_dl_sym(
+    vfs: *mut ffi::sqlite3_vfs,
+    handle: *mut c_void,
+    symbol: *const c_char,
+) -> Option<DlSym> {
+    unsafe {
+        let default = default_vfs(vfs);
+        (*default).xDlSym.unwrap()(default, handle, symbol)
+    }
+}
+
+unsafe extern "C" fn x_dl_close(vfs: *mut ffi::sqlite3_vfs, handle: *mut c_void) {
+    unsafe {
+        let default = default_vfs(vfs);
+        (*default).xDlClose.unwrap()(default, handle)
+    }
+}
+
+unsafe extern "C" fn x_randomness(vfs: *mut ffi::sqlite3_vfs, n: c_int, out: *mut c_char) -> c_int {
+    unsafe {
+        let default = default_vfs(vfs);
+        (*default).xRandomness.unwrap()(default, n, out)
+    }
+}
+
+unsafe extern "C" fn x_sleep(vfs: *mut ffi::sqlite3_vfs, microseconds: c_int) -> c_int {
+    unsafe {
+        let default = default_vfs(vfs);
+        (*default).xSleep.unwrap()(default, microseconds)
+    }
+}
+
+unsafe extern "C" fn x_current_time(vfs: *mut ffi::sqlite3_vfs, out: *mut f64) -> c_int {
+    unsafe {
+        let default = default_vfs(vfs);
+        (*default).xCurrentTime.unwrap()(default, out)
+    }
+}
+
+unsafe extern "C" fn x_get_last_error(
+    vfs: *mut ffi::sqlite3_vfs,
+    n: c_int,
+    out: *mut c_char,
+) -> c_int {
+    unsafe {
+        let default = default_vfs(vfs);
+        (*default).xGetLastError.unwrap()(default, n, out)
+    }
+}
+
+unsafe fn remote_object<'a>(file: *mut ffi::sqlite3_file) -> &'a RemoteObject {
+    unsafe { &*(*(file as *mut RemoteFile)).object }
+}
+
+unsafe extern "C" fn x_close(file: *mut ffi::sqlite3_file) -> c_int {
+    unsafe {
+        drop(Arc::from_raw((*(file as *mut RemoteFile)).object));
+    }
+
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn x_read(
+    file: *mut ffi::sqlite3_file,
+    buf: *mut c_void,
+    amount: c_int,
+    offset: ffi::sqlite3_int64,
+) -> c_int {
+    unsafe {
+        let out = std::slice::from_raw_parts_mut(buf as *mut u8, amount as usize);
+
+        match remote_object(file).read(out, offset as u64) {
+            Ok(n) if n == out.len() => ffi::SQLITE_OK,
+            Ok(n) => {
+                out[n..].fill(0);
+                ffi::SQLITE_IOERR_SHORT_READ
+            }
+            Err(e) => {
+                eprintln!("ranged read failed: {}", e);
+                ffi::SQLITE_IOERR_READ
+            }
+        }
+    }
+}
+
+unsafe extern "C" fn x_write(
+    _file: *mut ffi::sqlite3_file,
+    _buf: *const c_void,
+    _amount: c_int,
+    _offset: ffi::sqlite3_int64,
+) -> c_int {
+    ffi::SQLITE_READONLY
+}
+
+unsafe extern "C" fn x_truncate(_file: *mut ffi::sqlite3_file, _size: ffi::sqlite3_int64) -> c_int {
+    ffi::SQLITE_READONLY
+}
+
+unsafe extern "C" fn x_sync(_file: *mut ffi::sqlite3_file, _flags: c_int) -> c_int {
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn x_file_size(
+    file: *mut ffi::sqlite3_file,
+    size: *mut ffi::sqlite3_int64,
+) -> c_int {
+    unsafe {
+        *size = remote_object(file).size as ffi::sqlite3_int64;
+    }
+
+    ffi::SQLITE_OK
+}
+
+/// Remote shards are immutable, there is nothing to lock
+unsafe extern "C" fn x_lock(_file: *mut ffi::sqlite3_file, _lock: c_int) -> c_int {
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn x_check_reserved_lock(
+    _file: *mut ffi::sqlite3_file,
+    out: *mut c_int,
+) -> c_int {
+    unsafe {
+        *out = 0;
+    }
+
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn x_file_control(
+    _file: *mut ffi::sqlite3_file,
+    _op: c_int,
+    _arg: *mut c_void,
+) -> c_int {
+    ffi::SQLITE_NOTFOUND
+}
+
+unsafe extern "C" fn x_sector_size(_file: *mut ffi::sqlite3_file) -> c_int {
+    4096
+}
+
+unsafe extern "C" fn x_device_characteristics(_file: *mut ffi::sqlite3_file) -> c_int {
+    ffi::SQLITE_IOCAP_IMMUTABLE
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use sqlx::{SqlitePool, sqlite::SqliteConnectOptions};
+    use tempfile::{NamedTempFile, TempDir};
+
+    use super::*;
+    use crate::{db::connect_with_options, object_storage::LocalStore, schema::create_logs_table};
+
+    const ROWS: usize = 50_000;
+
+    /// A shard with `ROWS` rows in the local store, uploaded in WAL mode like workers do
+    async fn upload_shard(key: &str) -> (TempDir, Store, NamedTempFile) {
+        let dir = tempfile::tempdir().unwrap();
+        let store: Store = Arc::new(LocalStore::open(dir.path().to_owned()).unwrap());
+
+        let file = NamedTempFile::new().unwrap();
+        let pool = connect_with_options(&file.path().display().to_string())
+            .await
+            .unwrap();
+
+        create_logs_table(&pool).await.unwrap();
+
+        let mut tx = pool.begin().await.unwrap();
+
+        for i in 0..ROWS {
+            sqlx::query("INSERT INTO logs (id, timestamp, message) VALUES (?1, ?2, ?3)")
+                .bind(format!("row-{}", i))
+                .bind("2025-01-01 00:00:00")
+                .bind(format!("message {} {}", i, "x".repeat(200)))
+                .execute(&mut *tx)
+                .await
+                .unwrap();
+        }
+
+        tx.commit().await.unwrap();
+
+        sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+
+        store.put(key, file.path()).await.unwrap();
+
+        (dir, store, file)
+    }
+
+    async fn open(options: SqliteConnectOptions) -> SqlitePool {
+        SqlitePool::connect_with(options.read_only(true).immutable(true))
+            .await
+            .unwrap()
+    }
+
+    async fn query(pool: &SqlitePool, sql: &str) -> Vec<(String, String)> {
+        sqlx::query_as(sql).fetch_all(pool).await.unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn remote_queries_match_local_ones() {
+        let (_dir, store, file) = upload_shard("logs.remote.db").await;
+
+        let remote = open_remote(&store, "logs.remote.db").await.unwrap();
+
+        let local = open(SqliteConnectOptions::new().filename(file.path())).await;
+        let ranged = open(
+            SqliteConnectOptions::new()
+                .filename(remote.name())
+                .vfs(VFS_NAME),
+        )
+        .await;
+
+        for sql in [
+            "SELECT id, message FROM logs WHERE id = 'row-31337'",
+            "SELECT id, message FROM logs WHERE id >= 'row-4999' ORDER BY id LIMIT 20",
+            "SELECT CAST(COUNT(*) AS TEXT), MAX(id) FROM logs",
+        ] {
+            let expected = query(&local, sql).await;
+
+            assert!(!expected.is_empty());
+            assert_eq!(query(&ranged, sql).await, expected, "{}", sql);
+        }
+
+        ranged.close().await;
+        local.close().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn indexed_query_fetches_a_fraction_of_the_object() {
+        let (_dir, store, _file) = upload_shard("logs.indexed.db").await;
+
+        let remote = open_remote(&store, "logs.indexed.db").await.unwrap();
+
+        let pool = open(
+            SqliteConnectOptions::new()
+                .filename(remote.name())
+                .vfs(VFS_NAME),
+        )
+        .await;
+
+        let rows = query(&pool, "SELECT id, message FROM logs WHERE id = 'row-12345'").await;
+
+        pool.close().await;
+
+        assert_eq!(rows.len(), 1);
+
+        let fetched = remote.object.fetched_bytes.load(Ordering::Relaxed);
+
+        assert!(
+            fetched * 20 < remote.object.size,
+            "fetched {} of {} bytes",
+            fetched,
+            remote.object.size
+        );
+    }
+
+    /// An object of `chunks` chunks whose bytes are derived from their offset
+    fn generated(chunks: u64) -> RemoteShard {
+        let fetch: FetchRange = Box::new(|offset, len| {
+            Box::pin(async move { Ok((offset..offset + len).map(|i| (i % 251) as u8).collect()) })
+        });
+
+        register("generated", fetch, chunks * CHUNK_SIZE).unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reads_span_chunks_and_report_rollback_journal_mode() {
+        let remote = generated(3);
+        let object = remote.object.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let mut header = [0u8; 32];
+            assert_eq!(object.read(&mut header, 0).unwrap(), 32);
+            assert_eq!(&header[18..20], &[1, 1]);
+            assert_eq!(header[17], 17);
+
+            let mut across = vec![0u8; 100];
+            let offset = CHUNK_SIZE - 50;
+            assert_eq!(object.read(&mut across, offset).unwrap(), 100);
+            assert!(
+                across
+                    .iter()
+                    .enumerate()
+                    .all(|(i, byte)| *byte == ((offset + i as u64) % 251) as u8)
+            );
+
+            let mut tail = vec![0u8; 100];
+            assert_eq!(object.read(&mut tail, 3 * CHUNK_SIZE - 40).unwrap(), 40);
+            assert_eq!(object.read(&mut tail, 3 * CHUNK_SIZE).unwrap(), 0);
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn least_recently_used_chunks_are_evicted() {
+        let remote = generated(MAX_CACHED_CHUNKS as u64 + 1);
+        let object = remote.object.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let mut byte = [0u8; 1];
+
+            for index in 0..=MAX_CACHED_CHUNKS as u64 {
+                object.read(&mut byte, index * CHUNK_SIZE).unwrap();
+
+                // Chunk 1 stays in use, chunk 0 becomes the oldest one
+                object.read(&mut byte, CHUNK_SIZE).unwrap();
+            }
+
+            let chunks = object.chunks.lock().unwrap();
+
+            assert_eq!(chunks.chunks.len(), MAX_CACHED_CHUNKS);
+            assert!(!chunks.chunks.contains_key(&0));
+            assert!(chunks.chunks.contains_key(&1));
+            assert_eq!(
+                object.fetched_bytes.load(Ordering::Relaxed),
+                (MAX_CACHED_CHUNKS as u64 + 1) * CHUNK_SIZE
+            );
+        })
+        .await
+        .unwrap();
+    }
+}
@@ -1,16 +1,20 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use axum::{
     Json, Router,
-    extract::State,
+    extract::{Path, Query, State},
     response::IntoResponse,
-    routing::{get, post},
+    routing::{get, post, put},
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use crate::{
+    cluster::{self, WorkerStatus},
+    erasure::{self, DeleteByQuery, DeleteByQueryResult},
     errors::AppError,
-    messages::{Message, MessageLog},
-    shards::{self, ShardMetadata, schedule_query},
+    indices::{self, IndexSettings},
+    ingest::{self, AckMode},
+    messages::Message,
+    shards::{self, UnhealthyShard, schedule_query},
     state::ApiState,
 };
 
@@ -20,8 +24,13 @@ pub fn get_router(state: ApiState) -> Router<ApiState> {
     Router::new()
         .route("/", get(info))
         .route("/logs", post(logs))
-        .route("/_shard", post(store_shard))
         .route("/search", post(search))
+        .route("/_indices", get(list_indices))
+        .route("/_indices/{name}", put(update_index))
+        .route("/_admin/delete_by_query", post(delete_by_query))
+        .route("/_admin/unhealthy_shards", get(list_unhealthy_shards))
+        .route("/_cluster", get(cluster_status))
+        .route("/_cluster/{worker_id}/drain", post(drain_worker))
         .with_state(state.clone())
 }
 
@@ -40,48 +49,102 @@ async fn search(state: State<ApiState>, payload: Json<SearchPayload>) -> impl In
     let pattern: Vec<&str> = query.split("from ").collect();
     let pattern = pattern.get(1).unwrap_or(&"").trim();
     let pattern: Vec<&str> = pattern.split("where").collect();
-    let pattern = pattern.get(0).unwrap_or(&"").trim();
-
-    if let Ok(results) = schedule_query(
-        &state.master_db,
-        state.commands.clone(),
-        state.results.clone(),
-        &pattern,
-        &query,
-    )
-    .await
-    {
-        Json(results).into_response()
-    } else {
-        return AppError(anyhow::anyhow!("err")).into_response();
+    let pattern = pattern.first().unwrap_or(&"").trim();
+
+    match schedule_query(&state, pattern, &query).await {
+        Ok(results) => Json(results).into_response(),
+        Err(e) => AppError(e).into_response(),
     }
 }
 
-async fn store_shard(state: State<ApiState>, payload: Json<ShardMetadata>) -> impl IntoResponse {
-    if shards::store_shard(&state.master_db, &payload)
-        .await
-        .is_err()
-    {
-        return "error registering shard".into_response();
-    }
+async fn list_indices(state: State<ApiState>) -> Result<Json<Vec<IndexSettings>>, AppError> {
+    Ok(Json(indices::list_indices(&state.master_db).await?))
+}
+
+async fn update_index(
+    state: State<ApiState>,
+    Path(name): Path<String>,
+    Json(mut settings): Json<IndexSettings>,
+) -> Result<Json<IndexSettings>, AppError> {
+    settings.name = name;
+
+    indices::store_index(&state.master_db, &settings).await?;
+
+    state
+        .broadcast(&Message::IndexSettings(settings.clone()))
+        .await;
+
+    Ok(Json(settings))
+}
+
+async fn delete_by_query(
+    state: State<ApiState>,
+    Json(payload): Json<DeleteByQuery>,
+) -> Result<Json<DeleteByQueryResult>, AppError> {
+    Ok(Json(erasure::delete_by_query(&state, &payload).await?))
+}
+
+async fn list_unhealthy_shards(
+    state: State<ApiState>,
+) -> Result<Json<Vec<UnhealthyShard>>, AppError> {
+    Ok(Json(shards::list_unhealthy(&state.master_db).await?))
+}
+
+async fn cluster_status(state: State<ApiState>) -> Json<Vec<WorkerStatus>> {
+    Json(cluster::list(&state).await)
+}
+
+async fn drain_worker(
+    state: State<ApiState>,
+    Path(worker_id): Path<String>,
+) -> Result<&'static str, AppError> {
+    cluster::drain(&state, &worker_id).await?;
+
+    Ok("draining")
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct LogsPayload {
+    #[serde(default)]
+    messages: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct LogsParams {
+    #[serde(default)]
+    ack: AckMode,
+}
 
-    "acknowledged".into_response()
+#[derive(Serialize, Debug)]
+struct LogsResponse {
+    /// One batch per worker the logs were routed to
+    batches: Vec<String>,
+    ack: AckMode,
 }
 
-async fn logs(state: State<ApiState>) -> impl IntoResponse {
+async fn logs(
+    state: State<ApiState>,
+    Query(params): Query<LogsParams>,
+    payload: Option<Json<LogsPayload>>,
+) -> Result<Json<LogsResponse>, AppError> {
     // TODO: we should decide on mappings and the index automatically
+    let mut messages = payload
+        .map(|Json(payload)| payload.messages)
+        .unwrap_or_default();
+
+    if messages.is_empty() {
+        messages.push(format!("http log {}", time::UtcDateTime::now()));
+    }
 
-    state.commands.lock().await.push(
-        serde_json::to_string(&Message::Log(MessageLog {
-            log: format!("log message"),
-        }))
-        .unwrap(),
-    );
+    let batches = ingest::ingest(&state, messages, params.ack).await?;
 
-    "logged".into_response()
+    Ok(Json(LogsResponse {
+        batches,
+        ack: params.ack,
+    }))
 }
 
-pub async fn init_web(state: ApiState) -> Result<()> {
+pub async fn init_web(state: ApiState, address: &str) -> Result<()> {
     let cors_layer = CorsLayer::new()
         .allow_methods(AllowMethods::any())
         .allow_headers(AllowHeaders::any())
@@ -92,7 +155,12 @@ pub async fn init_web(state: ApiState) -> Result<()> {
         .with_state(state.clone())
         .layer(cors_layer);
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
+    let listener = tokio::net::TcpListener::bind(address)
+        .await
+        .with_context(|| format!("could not listen on {}", address))?;
+
+    println!("serving http on {}", listener.local_addr()?);
+
     axum::serve(listener, router).await?;
 
     Ok(())
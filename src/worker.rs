@@ -1,160 +1,1032 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
 
 use crate::{
-    get_s3_client,
-    messages::{Message, MessageSearchResponse},
-    shards::{QueryResult, Shard},
+    cache::{ShardCache, start_sweeper},
+    cluster::HEARTBEAT_INTERVAL,
+    config::NetworkConfig,
+    indices::IndexSettings,
+    integrity,
+    messages::{
+        Message, MessageCompactResponse, MessageHeartbeat, MessageLogAck, MessageRewriteResponse,
+        MessageSearchResponse, MessageShardCorrupt, MessageShardRegistered, MessageShardSealed,
+        MessageShardsHeld,
+    },
+    object_storage::{self, Store},
+    protocol::{self, Encoding, Hello, PROTOCOL_VERSION},
+    shards::{QueryResult, Shard, ShardReader},
 };
 
 use anyhow::Result;
 use tokio::{
-    io::{self, AsyncReadExt},
+    net::{TcpStream, tcp::OwnedWriteHalf},
+    signal::unix::{SignalKind, signal},
     sync::Mutex,
 };
 
-pub async fn init_worker() -> Result<()> {
-    let _ = tokio::spawn(start()).await?;
+/// First delay before connecting to the coordinator again, doubled after every failure
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// A draining worker gives up on jobs and registrations after this long
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Sealed shards not acknowledged by then are sent to the coordinator again
+const REGISTRATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// First delay before a failed upload is attempted again, doubled after every failure
+const UPLOAD_RETRY_DELAY: Duration = Duration::from_secs(5);
+const MAX_UPLOAD_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Registered shards stay served to live queries this long, for queries that read the
+/// catalog before the shard was in it
+const REGISTERED_GRACE: Duration = Duration::from_secs(60);
+
+pub async fn init_worker(
+    store: Store,
+    cache_dir: PathBuf,
+    cache_size: u64,
+    ranged_reads: bool,
+    network: &NetworkConfig,
+) -> Result<()> {
+    let reader = if ranged_reads {
+        ShardReader::Ranged(store.clone())
+    } else {
+        let cache = Arc::new(ShardCache::open(store.clone(), cache_dir, cache_size)?);
+        tokio::spawn(start_sweeper(cache.clone()));
+        ShardReader::Cached(cache)
+    };
+
+    let _ = tokio::spawn(start(store, reader, network.coordinator().to_owned())).await?;
 
     Ok(())
 }
 
-pub async fn start() -> Result<()> {
-    let client = get_s3_client();
+/// An uploaded shard and when it was last sent to the coordinator for registration
+struct UnregisteredShard {
+    shard: Shard,
+    sent: Option<Instant>,
+}
 
-    let socket = tokio::net::TcpSocket::new_v4()?;
-    let mut stream = socket.connect("127.0.0.1:6666".parse()?).await?;
+/// A sealed shard whose upload failed, attempted again from `retry_at` on
+struct FailedUpload {
+    shard: Shard,
+    delay: Duration,
+    retry_at: Instant,
+}
 
-    let (mut r, w) = stream.split();
+/// A shard in the catalog already, removed once `REGISTERED_GRACE` is over
+struct RegisteredShard {
+    shard: Shard,
+    at: Instant,
+}
 
-    println!("connected coordinator");
+/// Shards held by this worker and the settings they are rotated by
+#[derive(Clone)]
+struct WorkerState {
+    store: Store,
+    active: Arc<Mutex<Shard>>,
+    /// Shards rotated out but not registered in the catalog yet, still served to live queries
+    sealing: Arc<Mutex<Vec<Shard>>>,
+    /// Sealed shards waiting for another upload attempt
+    failed_uploads: Arc<Mutex<Vec<FailedUpload>>>,
+    /// Uploaded shards the coordinator has not acknowledged yet
+    unregistered: Arc<Mutex<Vec<UnregisteredShard>>>,
+    /// Shards the coordinator acknowledged lately, still served to live queries
+    registered: Arc<Mutex<Vec<RegisteredShard>>>,
+    /// Current coordinator connection, `None` while reconnecting
+    writer: Arc<Mutex<Option<Arc<Writer>>>>,
+    indices: Arc<Mutex<HashMap<String, IndexSettings>>>,
+    reader: ShardReader,
+    /// Compactions and rewrites in progress
+    running_jobs: Arc<AtomicUsize>,
+    running_queries: Arc<AtomicUsize>,
+    /// Set once asked to shut down, by a signal or by the coordinator
+    draining: Arc<AtomicBool>,
+    drain_started: Arc<AtomicBool>,
+}
 
-    let shard_ptr = Arc::new(Mutex::new(Shard::new(client.clone()).await?));
+impl WorkerState {
+    async fn new(store: Store, reader: ShardReader) -> Result<WorkerState> {
+        Ok(WorkerState {
+            active: Arc::new(Mutex::new(Shard::new(store.clone()).await?)),
+            store,
+            sealing: Arc::new(Mutex::new(vec![])),
+            failed_uploads: Arc::new(Mutex::new(vec![])),
+            unregistered: Arc::new(Mutex::new(vec![])),
+            registered: Arc::new(Mutex::new(vec![])),
+            writer: Arc::new(Mutex::new(None)),
+            indices: Arc::new(Mutex::new(HashMap::new())),
+            reader,
+            running_jobs: Arc::new(AtomicUsize::new(0)),
+            running_queries: Arc::new(AtomicUsize::new(0)),
+            draining: Arc::new(AtomicBool::new(false)),
+            drain_started: Arc::new(AtomicBool::new(false)),
+        })
+    }
 
-    let shard_clone = shard_ptr.clone();
+    async fn heartbeat(&self) -> MessageHeartbeat {
+        let (active_shard, active_rows) = {
+            let active = self.active.lock().await;
+            (Some(active.metadata().id.clone()), active.row_count())
+        };
 
-    let mut i = tokio::time::interval(Duration::from_secs(60));
+        let cache_bytes = match &self.reader {
+            ShardReader::Cached(cache) => cache.used_bytes(),
+            ShardReader::Ranged(_) => 0,
+        };
 
-    i.tick().await;
+        MessageHeartbeat {
+            active_shard,
+            active_rows,
+            sealing_shards: self.sealing.lock().await.len(),
+            cache_bytes,
+            running_jobs: self.running_jobs.load(Ordering::Relaxed),
+            running_queries: self.running_queries.load(Ordering::Relaxed),
+            downloading: object_storage::downloading(),
+        }
+    }
 
-    tokio::spawn(async move {
-        loop {
-            i.tick().await;
+    /// Seal the active shard when its index policy says so and upload it in the background
+    async fn rotate_if_needed(&self) -> Result<()> {
+        self.rotate(false).await
+    }
 
-            let shard_clone = shard_clone.clone();
-            let client_copy = client.clone();
+    /// Seal the active shard and upload it in the background. With `force` regardless of
+    /// the rotation policy, as long as it has rows.
+    async fn rotate(&self, force: bool) -> Result<()> {
+        let mut active = self.active.lock().await;
 
-            println!("sync to object storage started");
+        let settings = self
+            .indices
+            .lock()
+            .await
+            .get(&active.metadata().name)
+            .cloned();
 
-            let shard_to_sync = { shard_clone.lock().await.clone() };
+        let policy = settings
+            .as_ref()
+            .map(|settings| settings.rotation.clone())
+            .unwrap_or_default();
+        let codec = settings
+            .map(|settings| settings.compression)
+            .unwrap_or_default();
 
-            tokio::spawn(async move {
-                shard_to_sync
-                    .sync_shard_to_storage()
-                    .await
-                    .expect("error syncing shard");
-            });
+        let due = if force {
+            active.row_count() > 0
+        } else {
+            active.should_rotate(&policy).await?
+        };
 
-            // TODO: here, we should create a new shard
+        if !due {
+            return Ok(());
+        }
 
-            {
-                tokio::spawn(async move {
-                    let new_shard = Shard::new(client_copy.clone()).await.unwrap();
-                    let mut ptr = shard_clone.lock().await;
-                    *ptr = new_shard;
+        println!("sync to object storage started");
+
+        let mut shard_to_sync =
+            std::mem::replace(&mut *active, Shard::new(self.store.clone()).await?);
+
+        drop(active);
+
+        if let Err(e) = shard_to_sync.seal(codec).await {
+            eprintln!("could not seal shard: {}", e);
+        }
+
+        self.sealing.lock().await.push(shard_to_sync.clone());
+        self.upload(shard_to_sync, UPLOAD_RETRY_DELAY);
+
+        Ok(())
+    }
+
+    /// Upload a sealed shard in the background and send it for registration. After a
+    /// failure it is attempted again in `delay`.
+    fn upload(&self, mut shard: Shard, delay: Duration) {
+        let state = self.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = shard.upload().await {
+                eprintln!(
+                    "error syncing shard {}, retrying in {:?}: {}",
+                    &shard.metadata().id,
+                    delay,
+                    e
+                );
+
+                state.failed_uploads.lock().await.push(FailedUpload {
+                    shard,
+                    delay: (delay * 2).min(MAX_UPLOAD_RETRY_DELAY),
+                    retry_at: Instant::now() + delay,
                 });
+
+                return;
+            }
+
+            state
+                .unregistered
+                .lock()
+                .await
+                .push(UnregisteredShard { shard, sent: None });
+            state.register_sealed().await;
+        });
+    }
+
+    /// Upload the shards whose last attempt failed, once their delay is over
+    async fn retry_uploads(&self) {
+        let due: Vec<FailedUpload> = {
+            let mut failed_uploads = self.failed_uploads.lock().await;
+            let (due, waiting) = std::mem::take(&mut *failed_uploads)
+                .into_iter()
+                .partition(|failed| failed.retry_at <= Instant::now());
+
+            *failed_uploads = waiting;
+
+            due
+        };
+
+        for failed in due {
+            self.upload(failed.shard, failed.delay);
+        }
+    }
+
+    /// Send uploaded shards to the coordinator for registration, again if the last
+    /// attempt was not acknowledged in time. Shards stay served to live queries until then.
+    async fn register_sealed(&self) {
+        let Some(w) = self.writer.lock().await.clone() else {
+            return;
+        };
+
+        for unregistered in self.unregistered.lock().await.iter_mut() {
+            if unregistered
+                .sent
+                .is_some_and(|sent| sent.elapsed() < REGISTRATION_TIMEOUT)
+            {
+                continue;
+            }
+
+            let message = Message::ShardSealed(MessageShardSealed {
+                shard: unregistered.shard.metadata().clone(),
+            });
+
+            if let Err(e) = write_message(&w, &message).await {
+                eprintln!("could not send sealed shard: {}", e);
+                return;
+            }
+
+            unregistered.sent = Some(Instant::now());
+        }
+    }
+
+    async fn shard_registered(&self, ack: MessageShardRegistered) {
+        if let Some(error) = ack.error {
+            eprintln!(
+                "coordinator could not register shard {}: {}",
+                &ack.id, error
+            );
+
+            // Sent again on the next rotation check
+            for unregistered in self.unregistered.lock().await.iter_mut() {
+                if unregistered.shard.metadata().id == ack.id {
+                    unregistered.sent = None;
+                }
+            }
+
+            return;
+        }
+
+        println!("shard registered: {}", &ack.id);
+
+        self.unregistered
+            .lock()
+            .await
+            .retain(|unregistered| unregistered.shard.metadata().id != ack.id);
+
+        let mut sealing = self.sealing.lock().await;
+
+        if let Some(i) = sealing
+            .iter()
+            .position(|shard| shard.metadata().id == ack.id)
+        {
+            self.registered.lock().await.push(RegisteredShard {
+                shard: sealing.remove(i),
+                at: Instant::now(),
+            });
+        }
+    }
+
+    /// Delete the local copies of shards registered longer than `REGISTERED_GRACE` ago
+    async fn remove_registered(&self) {
+        let expired: Vec<RegisteredShard> = {
+            let mut registered = self.registered.lock().await;
+            let (expired, kept) = std::mem::take(&mut *registered)
+                .into_iter()
+                .partition(|registered| registered.at.elapsed() >= REGISTERED_GRACE);
+
+            *registered = kept;
+
+            expired
+        };
+
+        for registered in expired {
+            registered.shard.remove().await;
+        }
+    }
+
+    /// Ask the coordinator to stop sending work, it answers with `Drain`
+    async fn request_drain(&self) {
+        let Some(w) = self.writer.lock().await.clone() else {
+            println!("draining once reconnected");
+            return;
+        };
+
+        if let Err(e) = write_message(&w, &Message::Draining).await {
+            eprintln!("could not request drain, retrying once reconnected: {}", e);
+        }
+    }
+
+    /// Everything queued before `Drain` is handled by now. Wait for running jobs and shard
+    /// queries, seal and upload the active shard, and wait for the coordinator to register
+    /// every shard. Returns the shards still not registered once `DRAIN_TIMEOUT` ran out.
+    async fn drain(&self) -> Vec<String> {
+        let start = Instant::now();
+
+        println!("draining: waiting for running jobs and queries");
+
+        while (self.running_jobs.load(Ordering::Relaxed) > 0
+            || self.running_queries.load(Ordering::Relaxed) > 0)
+            && start.elapsed() < DRAIN_TIMEOUT
+        {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+
+        println!("draining: sealing the active shard");
+
+        if let Err(e) = self.rotate(true).await {
+            eprintln!("could not seal active shard: {}", e);
+        }
+
+        loop {
+            let remaining: Vec<String> = self
+                .sealing
+                .lock()
+                .await
+                .iter()
+                .map(|shard| shard.metadata().id.clone())
+                .collect();
+
+            if remaining.is_empty() || start.elapsed() > DRAIN_TIMEOUT {
+                return remaining;
             }
+
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    }
+
+    async fn drain_and_exit(self) {
+        let remaining = self.drain().await;
+
+        if !remaining.is_empty() {
+            eprintln!("drain timed out, shards not registered: {:?}", remaining);
+            std::process::exit(1);
+        }
+
+        println!("drained, exiting");
+        std::process::exit(0);
+    }
+
+    async fn connected(&self, w: Arc<Writer>) {
+        *self.writer.lock().await = Some(w);
+
+        // Acknowledgements of the previous connection may be lost
+        for unregistered in self.unregistered.lock().await.iter_mut() {
+            unregistered.sent = None;
+        }
+    }
+
+    async fn disconnected(&self) {
+        *self.writer.lock().await = None;
+    }
+
+    /// The active shard and every shard not registered in the catalog yet
+    async fn held_shards(&self) -> Vec<String> {
+        let mut shards = vec![self.active.lock().await.metadata().id.clone()];
+
+        shards.extend(
+            self.sealing
+                .lock()
+                .await
+                .iter()
+                .map(|shard| shard.metadata().id.clone()),
+        );
+
+        shards
+    }
+}
+
+/// Optional features of this worker, announced in the handshake
+fn capabilities(reader: &ShardReader) -> Vec<String> {
+    let mut capabilities = vec![
+        "zstd".to_owned(),
+        "sha256".to_owned(),
+        protocol::MSGPACK.to_owned(),
+    ];
+
+    if let ShardReader::Ranged(_) = reader {
+        capabilities.push("ranged_reads".to_owned());
+    }
+
+    capabilities
+}
+
+pub async fn start(store: Store, reader: ShardReader, coordinator: String) -> Result<()> {
+    // The id is kept across reconnects, so the coordinator knows the shards are still here
+    let hello = Hello {
+        protocol_version: PROTOCOL_VERSION,
+        worker_id: uuid::Uuid::new_v4().to_string(),
+        capabilities: capabilities(&reader),
+    };
+
+    let state = WorkerState::new(store, reader).await?;
+
+    tokio::spawn(handle_signals(state.clone()));
+
+    let state_copy = state.clone();
+
+    // Size and row limits are checked after every insert, this catches shards that age out
+    let mut i = tokio::time::interval(Duration::from_secs(5));
+
+    tokio::spawn(async move {
+        loop {
+            i.tick().await;
+
+            if let Err(e) = state_copy.rotate_if_needed().await {
+                eprintln!("could not rotate shard: {}", e);
+            }
+
+            state_copy.retry_uploads().await;
+            state_copy.register_sealed().await;
+            state_copy.remove_registered().await;
         }
     });
 
+    // Shards keep being written while the coordinator is away, they are served again on reconnect
+    let mut delay = RECONNECT_DELAY;
+
     loop {
-        // Wait for the socket to be readable
-        r.readable().await.expect("not readable");
+        match TcpStream::connect(&coordinator).await {
+            Ok(stream) => {
+                match serve(&state, &hello, stream, &mut delay).await {
+                    Ok(()) => println!("disconnected from coordinator"),
+                    Err(e) => eprintln!("connection to coordinator at {}: {:#}", &coordinator, e),
+                }
 
-        let mut len_bytes = [0u8; 4];
+                state.disconnected().await;
+            }
+            Err(e) => eprintln!(
+                "could not connect to coordinator at {}: {}",
+                &coordinator, e
+            ),
+        }
+
+        println!("reconnecting in {:?}", delay);
+
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+/// Drain on the first SIGINT or SIGTERM, exit right away on the second
+async fn handle_signals(state: WorkerState) -> Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
 
-        match r.read_exact(&mut len_bytes).await {
-            Ok(0) => {
+    loop {
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = interrupt.recv() => {}
+        }
+
+        if state.draining.swap(true, Ordering::Relaxed) {
+            eprintln!("exiting without draining");
+            std::process::exit(1);
+        }
+
+        println!("draining, signal again to exit right away");
+
+        state.request_drain().await;
+    }
+}
+
+/// Handshake with the coordinator and handle its commands until the connection is lost.
+/// `delay` is reset once the coordinator accepted the worker.
+async fn serve(
+    state: &WorkerState,
+    hello: &Hello,
+    stream: TcpStream,
+    delay: &mut Duration,
+) -> Result<()> {
+    let (mut r, mut w) = stream.into_split();
+
+    let welcome = protocol::client_handshake(&mut r, &mut w, hello).await?;
+    let encoding = welcome.encoding;
+
+    *delay = RECONNECT_DELAY;
+
+    let w = Arc::new(Writer {
+        w: Mutex::new(w),
+        encoding,
+    });
+
+    println!(
+        "connected coordinator as {}, protocol version: {}",
+        &hello.worker_id, welcome.protocol_version
+    );
+
+    let held = Message::ShardsHeld(MessageShardsHeld {
+        shards: state.held_shards().await,
+    });
+    write_message(&w, &held).await?;
+
+    state.connected(w.clone()).await;
+    state.register_sealed().await;
+
+    if state.draining.load(Ordering::Relaxed) {
+        state.request_drain().await;
+    }
+
+    let state_copy = state.clone();
+    let heartbeat_writer = w.clone();
+
+    let heartbeats = tokio::spawn(async move {
+        let mut i = tokio::time::interval(HEARTBEAT_INTERVAL);
+
+        loop {
+            i.tick().await;
+
+            let heartbeat = Message::Heartbeat(state_copy.heartbeat().await);
+
+            if let Err(e) = write_message(&heartbeat_writer, &heartbeat).await {
+                eprintln!("could not send heartbeat: {}", e);
+                break;
+            }
+        }
+    });
+
+    loop {
+        let message = match protocol::read_message(&mut r, encoding).await {
+            Ok(Some(message)) => message,
+            Ok(None) => {
                 println!("disconnected");
                 break;
             }
             Err(e) => {
-                eprintln!("{}", e);
+                eprintln!("{:#}", e);
+                break;
             }
-            _ => {}
         };
 
-        let len = u32::from_be_bytes(len_bytes) as usize;
+        match message {
+            Message::Log(message_log) => {
+                let committed = {
+                    let active = state.active.lock().await;
 
-        let mut buf = vec![0; len];
+                    active
+                        .insert_logs(&message_log)
+                        .await
+                        .map(|_| active.metadata().id.clone())
+                };
 
-        // Try to read data, this may still fail with `WouldBlock`
-        // if the readiness event is a false positive.
-        match r.read_exact(&mut buf).await {
-            Ok(n) => {
-                if n == 0 {
-                    continue;
-                }
+                // Unacknowledged batches are sent again by the coordinator
+                match committed {
+                    Ok(shard) => {
+                        let ack = MessageLogAck {
+                            batch: message_log.batch,
+                            shard,
+                        };
 
-                let message_string = String::from_utf8(buf[..n].into())?;
+                        write_message(&w, &Message::LogAck(ack)).await?;
+                    }
+                    Err(e) => eprintln!("could not write batch: {}", e),
+                }
 
-                let message: Message =
-                    serde_json::from_str(&message_string).expect("could not parse message");
+                if let Err(e) = state.rotate_if_needed().await {
+                    eprintln!("could not rotate shard: {}", e);
+                }
+            }
+            Message::SearchRequest(message_search_request) => {
+                let reader = state.reader.clone();
+                let w = w.clone();
+                let running_queries = state.running_queries.clone();
 
-                match message {
-                    Message::Log(message_log) => {
-                        shard_ptr.lock().await.create_log().await;
-                    }
-                    Message::SearchRequest(message_search_request) => {
-                        let shard_results = match shard_ptr
-                            .lock()
-                            .await
-                            .execute_shard_query(
-                                &message_search_request.shard,
-                                &message_search_request.query,
-                            )
-                            .await
-                        {
-                            Ok(shard_results) => shard_results,
-                            Err(e) => {
-                                println!(
-                                    "query failure, shard: {}, error: {}",
-                                    &message_search_request.shard.id, e
-                                );
-
-                                QueryResult {
-                                    items: vec![],
-                                    columns: vec![],
-                                }
-                            }
-                        };
+                running_queries.fetch_add(1, Ordering::Relaxed);
 
-                        let search_response = MessageSearchResponse {
+                // The coordinator times queries out from when it sent them, so one slow
+                // shard must not hold up the queries queued behind it
+                tokio::spawn(async move {
+                    let search_response = match Shard::execute_shard_query(
+                        &reader,
+                        &message_search_request.shard,
+                        &message_search_request.query,
+                    )
+                    .await
+                    {
+                        Ok(shard_results) => MessageSearchResponse {
                             id: message_search_request.id,
                             payload: shard_results,
-                        };
+                            error: None,
+                        },
+                        Err(e) => {
+                            println!(
+                                "query failure, shard: {}, error: {}",
+                                &message_search_request.shard.id, e
+                            );
 
-                        let search_response = Message::SearchResponse(search_response);
-                        let search_response = serde_json::to_vec(&search_response)?;
+                            report_corruption(&w, &e).await;
 
-                        if w.try_write(&search_response.len().to_be_bytes()).is_err() {
-                            println!("error");
+                            MessageSearchResponse {
+                                id: message_search_request.id,
+                                payload: QueryResult::empty(),
+                                error: Some(e.to_string()),
+                            }
                         }
-                        if w.try_write(&search_response).is_err() {
-                            println!("error");
+                    };
+
+                    let response = Message::SearchResponse(search_response);
+
+                    if let Err(e) = write_message(&w, &response).await {
+                        eprintln!("could not send search response: {}", e);
+                    }
+
+                    running_queries.fetch_sub(1, Ordering::Relaxed);
+                });
+            }
+            Message::LiveSearchRequest(message_live_search_request) => {
+                let mut local_shards = vec![state.active.lock().await.clone()];
+                local_shards.extend(state.sealing.lock().await.iter().cloned());
+                local_shards.extend(
+                    state
+                        .registered
+                        .lock()
+                        .await
+                        .iter()
+                        .map(|registered| registered.shard.clone()),
+                );
+
+                let mut shard_results = QueryResult::empty();
+
+                for shard in local_shards.iter().filter(|shard| {
+                    shard.metadata().name == message_live_search_request.index
+                        && !message_live_search_request
+                            .exclude
+                            .contains(&shard.metadata().id)
+                }) {
+                    match shard
+                        .execute_live_query(&message_live_search_request.query)
+                        .await
+                    {
+                        Ok(results) => shard_results.merge(results),
+                        Err(e) => {
+                            println!(
+                                "live query failure, shard: {}, error: {}",
+                                &shard.metadata().id,
+                                e
+                            );
                         }
                     }
-                    _ => {}
                 }
+
+                let search_response = MessageSearchResponse {
+                    id: message_live_search_request.id,
+                    payload: shard_results,
+                    error: None,
+                };
+
+                write_message(&w, &Message::SearchResponse(search_response)).await?;
             }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                continue;
+            Message::Drain => {
+                state.draining.store(true, Ordering::Relaxed);
+
+                // Keep handling messages meanwhile, registrations are acknowledged here
+                if !state.drain_started.swap(true, Ordering::Relaxed) {
+                    tokio::spawn(state.clone().drain_and_exit());
+                }
             }
-            Err(e) => {
-                eprintln!("{}", e);
-                break;
+            Message::ShardRegistered(message_shard_registered) => {
+                state.shard_registered(message_shard_registered).await;
+            }
+            Message::IndexSettings(index_settings) => {
+                println!("index settings updated: {:?}", &index_settings);
+
+                state
+                    .indices
+                    .lock()
+                    .await
+                    .insert(index_settings.name.clone(), index_settings);
             }
+            Message::CompactRequest(message_compact_request) => {
+                let store = state.store.clone();
+                let w = w.clone();
+                let running_jobs = state.running_jobs.clone();
+
+                running_jobs.fetch_add(1, Ordering::Relaxed);
+
+                // Merging can take minutes, keep serving ingestion and queries meanwhile
+                tokio::spawn(async move {
+                    let response = match Shard::merge(
+                        store,
+                        &message_compact_request.shards,
+                        message_compact_request.codec,
+                    )
+                    .await
+                    {
+                        Ok(merged) => MessageCompactResponse {
+                            id: message_compact_request.id,
+                            shard: Some(merged),
+                            error: None,
+                        },
+                        Err(e) => {
+                            println!("compaction failure, error: {}", e);
+
+                            report_corruption(&w, &e).await;
+
+                            MessageCompactResponse {
+                                id: message_compact_request.id,
+                                shard: None,
+                                error: Some(e.to_string()),
+                            }
+                        }
+                    };
+
+                    running_jobs.fetch_sub(1, Ordering::Relaxed);
+
+                    if let Err(e) = write_message(&w, &Message::CompactResponse(response)).await {
+                        eprintln!("{}", e);
+                    }
+                });
+            }
+            Message::RewriteRequest(message_rewrite_request) => {
+                let store = state.store.clone();
+                let w = w.clone();
+                let running_jobs = state.running_jobs.clone();
+
+                running_jobs.fetch_add(1, Ordering::Relaxed);
+
+                tokio::spawn(async move {
+                    let response = match Shard::rewrite(
+                        &store,
+                        &message_rewrite_request.shard,
+                        &message_rewrite_request.filter,
+                    )
+                    .await
+                    {
+                        Ok((shard, rows_removed)) => MessageRewriteResponse {
+                            id: message_rewrite_request.id,
+                            shard,
+                            rows_removed,
+                            error: None,
+                            pending: vec![],
+                        },
+                        Err(e) => {
+                            println!(
+                                "rewrite failure, shard: {}, error: {}",
+                                &message_rewrite_request.shard.id, e
+                            );
+
+                            report_corruption(&w, &e).await;
+
+                            MessageRewriteResponse {
+                                id: message_rewrite_request.id,
+                                shard: None,
+                                rows_removed: 0,
+                                error: Some(e.to_string()),
+                                pending: vec![],
+                            }
+                        }
+                    };
+
+                    running_jobs.fetch_sub(1, Ordering::Relaxed);
+
+                    if let Err(e) = write_message(&w, &Message::RewriteResponse(response)).await {
+                        eprintln!("{}", e);
+                    }
+                });
+            }
+            Message::LiveDeleteRequest(message_live_delete_request) => {
+                let index = &message_live_delete_request.index;
+                let filter = &message_live_delete_request.filter;
+
+                let mut response = MessageRewriteResponse {
+                    id: message_live_delete_request.id,
+                    shard: None,
+                    rows_removed: 0,
+                    error: None,
+                    pending: vec![],
+                };
+
+                {
+                    // Held so the shard can't be rotated and uploaded while rows are deleted
+                    let active = state.active.lock().await;
+
+                    if &active.metadata().name == index {
+                        match active.execute_live_delete(filter).await {
+                            Ok(rows_removed) => response.rows_removed += rows_removed,
+                            Err(e) => response.error = Some(e.to_string()),
+                        }
+                    }
+                }
+
+                // Deleting locally would not change their objects, they are rewritten instead
+                let mut sealed: Vec<Shard> = state.sealing.lock().await.clone();
+                sealed.extend(
+                    state
+                        .registered
+                        .lock()
+                        .await
+                        .iter()
+                        .map(|registered| registered.shard.clone()),
+                );
+
+                for shard in sealed
+                    .iter()
+                    .filter(|shard| &shard.metadata().name == index)
+                {
+                    match shard.count_matching(filter).await {
+                        Ok(0) => {}
+                        Ok(_) => response.pending.push(shard.metadata().clone()),
+                        Err(e) => response.error = Some(e.to_string()),
+                    }
+                }
+
+                write_message(&w, &Message::RewriteResponse(response)).await?;
+            }
+            _ => {}
         }
     }
 
+    heartbeats.abort();
+
     Ok(())
 }
+
+/// Tell the coordinator about a shard that failed its checks, so it is left out of queries
+async fn report_corruption(w: &Writer, e: &anyhow::Error) {
+    let Some(corrupt) = integrity::corrupt_shard(e) else {
+        return;
+    };
+
+    let message = Message::ShardCorrupt(MessageShardCorrupt {
+        storage_key: corrupt.storage_key.clone(),
+        reason: corrupt.reason.clone(),
+    });
+
+    if let Err(e) = write_message(w, &message).await {
+        eprintln!("could not report corrupt shard: {}", e);
+    }
+}
+
+/// Write half of the coordinator connection, with the encoding agreed on in the handshake
+struct Writer {
+    w: Mutex<OwnedWriteHalf>,
+    encoding: Encoding,
+}
+
+async fn write_message(w: &Writer, message: &Message) -> Result<()> {
+    protocol::write_message(&mut *w.w.lock().await, message, w.encoding).await
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::NamedTempFile;
+    use tokio::net::{TcpListener, tcp::OwnedReadHalf};
+
+    use super::*;
+    use crate::codec::Codec;
+    use crate::db::connect_with_options;
+    use crate::messages::{MessageLiveSearchRequest, MessageSearchRequest};
+    use crate::object_storage::LocalStore;
+    use crate::schema::create_logs_table;
+    use crate::shards::{DEFAULT_INDEX, ShardMetadata};
+
+    /// Takes long enough that draining would be over before it without waiting for it
+    const SLOW_QUERY: &str = "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 1000000) SELECT COUNT(*) AS count FROM n";
+
+    async fn uploaded_shard(store: &Store) -> ShardMetadata {
+        let file = NamedTempFile::new().unwrap();
+        let pool = connect_with_options(&file.path().display().to_string())
+            .await
+            .unwrap();
+
+        create_logs_table(&pool).await.unwrap();
+
+        sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+
+        store.put("logs.slow.db", file.path()).await.unwrap();
+
+        ShardMetadata {
+            name: DEFAULT_INDEX.to_owned(),
+            id: "slow".to_owned(),
+            storage_key: "logs.slow.db".to_owned(),
+            timestamp: "2025-01-01 00:00:00".to_owned(),
+            end_timestamp: None,
+            size_bytes: 0,
+            row_count: 0,
+            codec: Codec::None,
+            sha256: None,
+        }
+    }
+
+    /// Read messages until the search response for `id`, heartbeats and others are skipped
+    async fn response(
+        r: &mut OwnedReadHalf,
+        encoding: Encoding,
+        id: &str,
+    ) -> MessageSearchResponse {
+        loop {
+            match protocol::read_message(r, encoding).await.unwrap() {
+                Some(Message::SearchResponse(response)) if response.id == id => return response,
+                Some(_) => continue,
+                None => panic!("worker disconnected"),
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn draining_answers_queued_shard_queries_first() {
+        let store_dir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let store: Store = Arc::new(LocalStore::open(store_dir.path().to_owned()).unwrap());
+        let cache = ShardCache::open(store.clone(), cache_dir.path().to_owned(), 1 << 30).unwrap();
+
+        let shard = uploaded_shard(&store).await;
+        let state = WorkerState::new(store, ShardReader::Cached(Arc::new(cache)))
+            .await
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let worker = state.clone();
+        tokio::spawn(async move {
+            let hello = Hello {
+                protocol_version: PROTOCOL_VERSION,
+                worker_id: "worker-1".to_owned(),
+                capabilities: vec![],
+            };
+            let stream = TcpStream::connect(address).await.unwrap();
+            let _ = serve(&worker, &hello, stream, &mut RECONNECT_DELAY.clone()).await;
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let (mut r, mut w) = stream.into_split();
+        let (_, welcome) = protocol::server_handshake(&mut r, &mut w, &[])
+            .await
+            .unwrap();
+        let encoding = welcome.encoding;
+
+        let search = Message::SearchRequest(MessageSearchRequest {
+            query: SLOW_QUERY.to_owned(),
+            id: "shard".to_owned(),
+            shard,
+        });
+        let live = Message::LiveSearchRequest(MessageLiveSearchRequest {
+            query: "SELECT 1 AS one".to_owned(),
+            id: "live".to_owned(),
+            index: DEFAULT_INDEX.to_owned(),
+            exclude: vec![],
+        });
+
+        protocol::write_message(&mut w, &search, encoding)
+            .await
+            .unwrap();
+        protocol::write_message(&mut w, &live, encoding)
+            .await
+            .unwrap();
+
+        // Commands are handled in order, the shard query is running once this is answered
+        response(&mut r, encoding, "live").await;
+
+        assert!(state.drain().await.is_empty());
+
+        // Sent before draining was over, so it is waiting on the socket already
+        let answered = tokio::time::timeout(
+            Duration::from_millis(200),
+            response(&mut r, encoding, "shard"),
+        )
+        .await
+        .expect("shard query answered after draining");
+
+        assert_eq!(answered.error, None);
+        assert_eq!(answered.payload.items[0]["count"], "1000000");
+    }
+}